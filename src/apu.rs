@@ -0,0 +1,984 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::cmp;
+
+use error::Error;
+use state::{StateReader, StateWriter};
+
+// All internal timing is done in T-cycles (4 per emulated M-cycle)
+const CLOCK_RATE: u32 = 4194304;
+const FRAME_SEQUENCER_PERIOD: i32 = 8192;
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+const WAVE_RAM_SIZE: usize = 16;
+
+const DUTY_PATTERNS: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Masks for bits that always read back as 1, indexed from 0xFF10
+const READ_MASKS: [u8; 0x30] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[derive(Copy, Clone, Debug)]
+struct Length {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length {
+            counter: 0,
+            max: max,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Returns false once the counter expires
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.counter = cmp::min(try!(reader.read_u16()), self.max);
+        self.enabled = try!(reader.read_bool());
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Envelope {
+    reg: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            reg: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn period(&self) -> u8 {
+        self.reg & 0b111
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.reg & 0xF8 != 0
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.reg >> 4;
+        self.timer = self.period();
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.reg & 0x08 != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.reg & 0x08 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.reg);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.reg = try!(reader.read_u8());
+        self.volume = try!(reader.read_u8()) & 0xF;
+        self.timer = try!(reader.read_u8()) & 0b111;
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Sweep {
+    reg: u8,
+    shadow: u16,
+    timer: u8,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            reg: 0,
+            shadow: 0,
+            timer: 0,
+            enabled: false,
+        }
+    }
+
+    fn period(&self) -> u8 {
+        (self.reg >> 4) & 0b111
+    }
+
+    fn shift(&self) -> u8 {
+        self.reg & 0b111
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.reg & 0x08 != 0 {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.reg);
+        writer.write_u16(self.shadow);
+        writer.write_u8(self.timer);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.reg = try!(reader.read_u8());
+        self.shadow = try!(reader.read_u16()) & 0x7FF;
+        self.timer = try!(reader.read_u8());
+        self.enabled = try!(reader.read_bool());
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SquareChannel {
+    enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    frequency: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl SquareChannel {
+    fn new() -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.duty_pos);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.timer as u32);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enabled = try!(reader.read_bool());
+        self.duty = try!(reader.read_u8()) & 0b11;
+        self.duty_pos = try!(reader.read_u8()) % 8;
+        self.frequency = try!(reader.read_u16()) & 0x7FF;
+        self.timer = try!(reader.read_u32()) as i32;
+        try!(self.length.load_state(reader));
+        self.envelope.load_state(reader)
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (DUTY_PATTERNS[self.duty as usize] >> self.duty_pos) & 0x1 != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    length: Length,
+    ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            length: Length::new(256),
+            ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.timer as u32);
+        writer.write_u8(self.position);
+        self.length.save_state(writer);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enabled = try!(reader.read_bool());
+        self.dac_enabled = try!(reader.read_bool());
+        self.volume_code = try!(reader.read_u8()) & 0b11;
+        self.frequency = try!(reader.read_u16()) & 0x7FF;
+        self.timer = try!(reader.read_u32()) as i32;
+        self.position = try!(reader.read_u8()) % 32;
+        try!(self.length.load_state(reader));
+        reader.read_bytes(&mut self.ram)
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let byte = self.ram[self.position as usize / 2];
+        let sample = if self.position % 2 == 0 { byte >> 4 } else { byte & 0xF };
+        match self.volume_code {
+            0 => 0,
+            code => sample >> (code - 1)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct NoiseChannel {
+    enabled: bool,
+    reg: u8,
+    lfsr: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            reg: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[(self.reg & 0b111) as usize] << (self.reg >> 4)
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            // 7-bit mode
+            if self.reg & 0x08 != 0 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.reg);
+        writer.write_u16(self.lfsr);
+        writer.write_u32(self.timer as u32);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enabled = try!(reader.read_bool());
+        self.reg = try!(reader.read_u8());
+        self.lfsr = try!(reader.read_u16()) & 0x7FFF;
+        self.timer = try!(reader.read_u32()) as i32;
+        try!(self.length.load_state(reader));
+        self.envelope.load_state(reader)
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 0x1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+// Models the output capacitor that removes the DC offset of the DACs
+struct HighPass {
+    charge: f32,
+    factor: f32,
+}
+
+impl HighPass {
+    fn new(sample_rate: u32) -> HighPass {
+        HighPass {
+            charge: 0.0,
+            factor: HighPass::factor(sample_rate),
+        }
+    }
+
+    fn factor(sample_rate: u32) -> f32 {
+        0.999958f32.powf(CLOCK_RATE as f32 / sample_rate as f32)
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.charge;
+        self.charge = input - output * self.factor;
+        output
+    }
+}
+
+pub struct Apu {
+    enabled: bool,
+    square1: SquareChannel,
+    sweep: Sweep,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+
+    // NR50/NR51
+    master_volume: u8,
+    panning: u8,
+
+    frame_clock: i32,
+    frame_step: u8,
+
+    sample_rate: u32,
+    sample_clock: u32,
+    sample_acc: (u32, u32),
+    sample_acc_cycles: u32,
+    filters: (HighPass, HighPass),
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            enabled: false,
+            square1: SquareChannel::new(),
+            sweep: Sweep::new(),
+            square2: SquareChannel::new(),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+
+            master_volume: 0,
+            panning: 0,
+
+            frame_clock: FRAME_SEQUENCER_PERIOD,
+            frame_step: 0,
+
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0,
+            sample_acc: (0, 0),
+            sample_acc_cycles: 0,
+            filters: (HighPass::new(DEFAULT_SAMPLE_RATE), HighPass::new(DEFAULT_SAMPLE_RATE)),
+            samples: Vec::new(),
+        }
+    }
+
+    // Register state the DMG boot ROM leaves behind, without its chime playing
+    pub fn init_post_boot(&mut self) {
+        let regs: [(u16, u8); 17] = [
+            (0x26, 0x80), (0x10, 0x80), (0x11, 0xBF), (0x12, 0xF3), (0x14, 0x3F),
+            (0x16, 0x3F), (0x17, 0x00), (0x19, 0x3F), (0x1A, 0x7F), (0x1B, 0xFF),
+            (0x1C, 0x9F), (0x1E, 0x3F), (0x20, 0xFF), (0x21, 0x00), (0x22, 0x00),
+            (0x24, 0x77), (0x25, 0xF3),
+        ];
+        for &(addr, value) in regs.iter() {
+            self.write_reg(addr, value);
+        }
+    }
+
+    // Can be adjusted while running, e.g. for audio rate control
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.filters.0.factor = HighPass::factor(sample_rate);
+        self.filters.1.factor = HighPass::factor(sample_rate);
+    }
+
+    // Moves all generated samples to `out`, as interleaved left/right pairs
+    pub fn drain_samples(&mut self, out: &mut Vec<i16>) {
+        out.extend_from_slice(&self.samples);
+        self.samples.clear();
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.square1.save_state(writer);
+        self.sweep.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.write_u8(self.master_volume);
+        writer.write_u8(self.panning);
+        writer.write_u32(self.frame_clock as u32);
+        writer.write_u8(self.frame_step);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enabled = try!(reader.read_bool());
+        try!(self.square1.load_state(reader));
+        try!(self.sweep.load_state(reader));
+        try!(self.square2.load_state(reader));
+        try!(self.wave.load_state(reader));
+        try!(self.noise.load_state(reader));
+        self.master_volume = try!(reader.read_u8());
+        self.panning = try!(reader.read_u8());
+        self.frame_clock = try!(reader.read_u32()) as i32;
+        self.frame_step = try!(reader.read_u8()) % 8;
+        Ok(())
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        let cycles = cycles as i32 * 4;
+
+        if self.enabled {
+            self.frame_clock -= cycles;
+            if self.frame_clock <= 0 {
+                self.frame_clock += FRAME_SEQUENCER_PERIOD;
+                self.clock_frame_sequencer();
+            }
+
+            self.square1.step(cycles);
+            self.square2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+        }
+
+        self.generate_samples(cycles as u32);
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        // Step:     0   1   2   3   4   5   6   7
+        // Length:   x       x       x       x
+        // Sweep:            x               x
+        // Envelope:                             x
+        if self.frame_step % 2 == 0 {
+            self.square1.enabled &= self.square1.length.clock();
+            self.square2.enabled &= self.square2.length.clock();
+            self.wave.enabled &= self.wave.length.clock();
+            self.noise.enabled &= self.noise.length.clock();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+
+        self.sweep.reload_timer();
+        if !self.sweep.enabled || self.sweep.period() == 0 {
+            return;
+        }
+
+        let frequency = self.sweep.next_frequency();
+        if frequency > 2047 {
+            self.square1.enabled = false;
+        } else if self.sweep.shift() != 0 {
+            self.sweep.shadow = frequency;
+            self.square1.frequency = frequency;
+
+            // The new frequency is checked for overflow once more
+            if self.sweep.next_frequency() > 2047 {
+                self.square1.enabled = false;
+            }
+        }
+    }
+
+    fn trigger_sweep(&mut self) {
+        self.sweep.shadow = self.square1.frequency;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.period() != 0 || self.sweep.shift() != 0;
+        if self.sweep.shift() != 0 && self.sweep.next_frequency() > 2047 {
+            self.square1.enabled = false;
+        }
+    }
+
+    fn mix(&self) -> (u32, u32) {
+        // Each DAC maps 0..15 to a positive value here, the DC offset
+        // is removed by the high-pass filter afterwards
+        let outputs = [
+            (self.square1.output(), self.square1.envelope.dac_enabled()),
+            (self.square2.output(), self.square2.envelope.dac_enabled()),
+            (self.wave.output(), self.wave.dac_enabled),
+            (self.noise.output(), self.noise.envelope.dac_enabled()),
+        ];
+
+        let mut left = 0;
+        let mut right = 0;
+        for (i, &(value, dac_enabled)) in outputs.iter().enumerate() {
+            if !dac_enabled {
+                continue;
+            }
+            if self.panning & (0x10 << i) != 0 {
+                left += value as u32;
+            }
+            if self.panning & (0x01 << i) != 0 {
+                right += value as u32;
+            }
+        }
+
+        let left_volume = ((self.master_volume >> 4) & 0b111) as u32 + 1;
+        let right_volume = (self.master_volume & 0b111) as u32 + 1;
+        (left * left_volume, right * right_volume)
+    }
+
+    fn generate_samples(&mut self, cycles: u32) {
+        // Box filter over all cycles that make up one output sample
+        let (left, right) = if self.enabled { self.mix() } else { (0, 0) };
+        self.sample_acc.0 += left * cycles;
+        self.sample_acc.1 += right * cycles;
+        self.sample_acc_cycles += cycles;
+
+        self.sample_clock += cycles * self.sample_rate;
+        while self.sample_clock >= CLOCK_RATE {
+            self.sample_clock -= CLOCK_RATE;
+
+            let (acc_left, acc_right) = self.sample_acc;
+            let n = cmp::max(self.sample_acc_cycles, 1) as f32;
+            let (avg_left, avg_right) = if self.sample_acc_cycles > 0 {
+                (acc_left as f32 / n, acc_right as f32 / n)
+            } else {
+                (left as f32, right as f32)
+            };
+            self.sample_acc = (0, 0);
+            self.sample_acc_cycles = 0;
+
+            // Maximum amplitude: 4 channels * 15 * 8 = 480
+            let scale = 32767.0 / 480.0;
+            let out_left = self.filters.0.filter(avg_left) * scale;
+            let out_right = self.filters.1.filter(avg_right) * scale;
+
+            // Don't grow without bounds if nobody is pulling samples
+            if self.samples.len() < 2 * self.sample_rate as usize {
+                self.samples.push(out_left as i16);
+                self.samples.push(out_right as i16);
+            }
+        }
+    }
+
+    fn power_off(&mut self) {
+        let wave_ram = self.wave.ram;
+        self.square1 = SquareChannel::new();
+        self.sweep = Sweep::new();
+        self.square2 = SquareChannel::new();
+        self.wave = WaveChannel::new();
+        self.wave.ram = wave_ram;
+        self.noise = NoiseChannel::new();
+        self.master_volume = 0;
+        self.panning = 0;
+    }
+
+    fn read_channel_status(&self) -> u8 {
+        (self.enabled as u8) << 7
+            | (self.noise.enabled as u8) << 3
+            | (self.wave.enabled as u8) << 2
+            | (self.square2.enabled as u8) << 1
+            | self.square1.enabled as u8
+    }
+
+    // IO: 0xFF10-0xFF3F
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        let value = match addr {
+            0x10 => self.sweep.reg,
+            0x11 => self.square1.duty << 6,
+            0x12 => self.square1.envelope.reg,
+            0x14 => (self.square1.length.enabled as u8) << 6,
+            0x16 => self.square2.duty << 6,
+            0x17 => self.square2.envelope.reg,
+            0x19 => (self.square2.length.enabled as u8) << 6,
+            0x1A => (self.wave.dac_enabled as u8) << 7,
+            0x1C => self.wave.volume_code << 5,
+            0x1E => (self.wave.length.enabled as u8) << 6,
+            0x21 => self.noise.envelope.reg,
+            0x22 => self.noise.reg,
+            0x23 => (self.noise.length.enabled as u8) << 6,
+            0x24 => self.master_volume,
+            0x25 => self.panning,
+            0x26 => self.read_channel_status(),
+            0x30...0x3F => self.wave.ram[(addr - 0x30) as usize],
+            _ => 0x00
+        };
+        value | READ_MASKS[(addr - 0x10) as usize]
+    }
+
+    pub fn write_reg(&mut self, addr: u16, value: u8) {
+        // While powered off, only NR52 and the wave RAM are writable
+        if !self.enabled && addr != 0x26 && addr < 0x30 {
+            return;
+        }
+
+        match addr {
+            // Square 1
+            0x10 => self.sweep.reg = value,
+            0x11 => {
+                self.square1.duty = value >> 6;
+                self.square1.length.load(value & 0x3F);
+            },
+            0x12 => {
+                self.square1.envelope.reg = value;
+                self.square1.enabled &= self.square1.envelope.dac_enabled();
+            },
+            0x13 => self.square1.frequency = (self.square1.frequency & 0x700) | value as u16,
+            0x14 => {
+                self.square1.frequency = (self.square1.frequency & 0xFF)
+                                         | ((value as u16 & 0x7) << 8);
+                self.square1.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.square1.trigger();
+                    self.trigger_sweep();
+                }
+            },
+
+            // Square 2
+            0x16 => {
+                self.square2.duty = value >> 6;
+                self.square2.length.load(value & 0x3F);
+            },
+            0x17 => {
+                self.square2.envelope.reg = value;
+                self.square2.enabled &= self.square2.envelope.dac_enabled();
+            },
+            0x18 => self.square2.frequency = (self.square2.frequency & 0x700) | value as u16,
+            0x19 => {
+                self.square2.frequency = (self.square2.frequency & 0xFF)
+                                         | ((value as u16 & 0x7) << 8);
+                self.square2.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.square2.trigger();
+                }
+            },
+
+            // Wave
+            0x1A => {
+                self.wave.dac_enabled = value & 0x80 != 0;
+                self.wave.enabled &= self.wave.dac_enabled;
+            },
+            0x1B => self.wave.length.load(value),
+            0x1C => self.wave.volume_code = (value >> 5) & 0b11,
+            0x1D => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0x1E => {
+                self.wave.frequency = (self.wave.frequency & 0xFF)
+                                      | ((value as u16 & 0x7) << 8);
+                self.wave.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.wave.trigger();
+                }
+            },
+
+            // Noise
+            0x20 => self.noise.length.load(value & 0x3F),
+            0x21 => {
+                self.noise.envelope.reg = value;
+                self.noise.enabled &= self.noise.envelope.dac_enabled();
+            },
+            0x22 => self.noise.reg = value,
+            0x23 => {
+                self.noise.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.noise.trigger();
+                }
+            },
+
+            // Control
+            0x24 => self.master_volume = value,
+            0x25 => self.panning = value,
+            0x26 => {
+                let enabled = value & 0x80 != 0;
+                if self.enabled && !enabled {
+                    self.power_off();
+                } else if !self.enabled && enabled {
+                    self.frame_clock = FRAME_SEQUENCER_PERIOD;
+                    self.frame_step = 0;
+                }
+                self.enabled = enabled;
+            },
+
+            0x30...0x3F => self.wave.ram[(addr - 0x30) as usize] = value,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Apu, READ_MASKS};
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write_reg(0x26, 0x80);
+        apu
+    }
+
+    // Runs the APU until the frame sequencer was clocked `steps` times
+    fn clock_frame_sequencer(apu: &mut Apu, steps: u32) {
+        // 8192 T-cycles are 2048 M-cycles per step
+        for _ in 0..steps * 16 {
+            apu.step(128);
+        }
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = powered_apu();
+        apu.write_reg(0x12, 0xF0);
+        // Length 64 - 62 = 2, triggered with the length counter enabled
+        apu.write_reg(0x11, 0x3E);
+        apu.write_reg(0x14, 0xC0);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x01);
+
+        clock_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x01);
+
+        // Step 1 doesn't clock the length, step 2 expires it
+        clock_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x01);
+        clock_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_sweep_overflow() {
+        let mut apu = powered_apu();
+        apu.write_reg(0x12, 0xF0);
+
+        // Period 1, increasing, shift 1: 0x500 + 0x280 still fits, but
+        // the check after the first sweep step overflows
+        apu.write_reg(0x10, 0x11);
+        apu.write_reg(0x13, 0x00);
+        apu.write_reg(0x14, 0x85);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x01);
+
+        // The sweep is clocked in step 2
+        clock_frame_sequencer(&mut apu, 2);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x01);
+        clock_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x00);
+
+        // An overflow already when triggering disables the channel at once
+        apu.write_reg(0x13, 0xFF);
+        apu.write_reg(0x14, 0x87);
+        assert_eq!(apu.read_reg(0x26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_power_off() {
+        let mut apu = powered_apu();
+        for addr in 0x10..0x26 {
+            apu.write_reg(addr, 0xFF);
+        }
+        apu.write_reg(0x30, 0x12);
+
+        apu.write_reg(0x26, 0x00);
+        assert_eq!(apu.read_reg(0x26), 0x70);
+        for addr in 0x10..0x26 {
+            assert_eq!(apu.read_reg(addr), READ_MASKS[(addr - 0x10) as usize]);
+        }
+
+        // Only the wave RAM stays writable
+        apu.write_reg(0x24, 0x77);
+        apu.write_reg(0x12, 0xF0);
+        apu.write_reg(0x31, 0x34);
+        assert_eq!(apu.read_reg(0x24), 0x00);
+        assert_eq!(apu.read_reg(0x12), 0x00);
+        assert_eq!(apu.read_reg(0x30), 0x12);
+        assert_eq!(apu.read_reg(0x31), 0x34);
+
+        apu.write_reg(0x26, 0x80);
+        apu.write_reg(0x24, 0x77);
+        assert_eq!(apu.read_reg(0x24), 0x77);
+    }
+
+    #[test]
+    fn test_read_masks() {
+        let mut apu = powered_apu();
+        assert_eq!(apu.read_reg(0x10), 0x80);
+        assert_eq!(apu.read_reg(0x11), 0x3F);
+        assert_eq!(apu.read_reg(0x13), 0xFF);
+        assert_eq!(apu.read_reg(0x14), 0xBF);
+        assert_eq!(apu.read_reg(0x1A), 0x7F);
+        assert_eq!(apu.read_reg(0x1C), 0x9F);
+        assert_eq!(apu.read_reg(0x20), 0xFF);
+        assert_eq!(apu.read_reg(0x26), 0xF0);
+        for addr in 0x27..0x30 {
+            assert_eq!(apu.read_reg(addr), 0xFF);
+        }
+
+        // Write-only bits read back as 1, the others as written
+        apu.write_reg(0x11, 0x80);
+        assert_eq!(apu.read_reg(0x11), 0xBF);
+        apu.write_reg(0x1C, 0x40);
+        assert_eq!(apu.read_reg(0x1C), 0xDF);
+        apu.write_reg(0x10, 0x25);
+        assert_eq!(apu.read_reg(0x10), 0xA5);
+        apu.write_reg(0x25, 0x5A);
+        assert_eq!(apu.read_reg(0x25), 0x5A);
+    }
+
+    #[test]
+    fn test_channel_status() {
+        let mut apu = powered_apu();
+
+        apu.write_reg(0x12, 0xF0);
+        apu.write_reg(0x14, 0x80);
+        assert_eq!(apu.read_reg(0x26), 0xF1);
+        apu.write_reg(0x17, 0xF0);
+        apu.write_reg(0x19, 0x80);
+        assert_eq!(apu.read_reg(0x26), 0xF3);
+        apu.write_reg(0x1A, 0x80);
+        apu.write_reg(0x1E, 0x80);
+        assert_eq!(apu.read_reg(0x26), 0xF7);
+        apu.write_reg(0x21, 0xF0);
+        apu.write_reg(0x23, 0x80);
+        assert_eq!(apu.read_reg(0x26), 0xFF);
+
+        // Turning off the DAC disables the channel
+        apu.write_reg(0x17, 0x00);
+        assert_eq!(apu.read_reg(0x26), 0xFD);
+
+        // The status bits are read-only
+        apu.write_reg(0x26, 0x8F);
+        assert_eq!(apu.read_reg(0x26), 0xFD);
+    }
+}
@@ -0,0 +1,145 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use sdl2;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use std::cmp;
+use std::collections::VecDeque;
+
+const SAMPLE_RATE: i32 = 44100;
+const DEVICE_BUFFER_SIZE: u16 = 1024;
+
+// Number of stereo frames the frontend tries to keep queued, and the
+// point at which new samples are dropped
+const TARGET_FILL: usize = 2 * DEVICE_BUFFER_SIZE as usize;
+const MAX_FILL: usize = 4 * TARGET_FILL;
+
+// Maximum deviation from the nominal sample rate for rate control
+const MAX_RATE_DELTA: f64 = 0.005;
+
+const VOLUME_STEP: u8 = 10;
+
+struct SampleQueue {
+    samples: VecDeque<i16>,
+    last: (i16, i16),
+}
+
+impl AudioCallback for SampleQueue {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        for frame in out.chunks_mut(2) {
+            // On underflow, hold the last value to avoid a click
+            if self.samples.len() >= 2 {
+                self.last = (self.samples.pop_front().unwrap(),
+                             self.samples.pop_front().unwrap());
+            }
+            frame[0] = self.last.0;
+            if frame.len() > 1 {
+                frame[1] = self.last.1;
+            }
+        }
+    }
+}
+
+pub struct Audio {
+    device: AudioDevice<SampleQueue>,
+    sample_rate: u32,
+    volume: u8,
+    muted: bool,
+}
+
+impl Audio {
+    pub fn new(context: &sdl2::Sdl) -> Result<Audio, String> {
+        let subsystem = try!(context.audio());
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(2),
+            samples: Some(DEVICE_BUFFER_SIZE),
+        };
+
+        let mut sample_rate = SAMPLE_RATE as u32;
+        let device = try!(subsystem.open_playback(None, &spec, |spec| {
+            sample_rate = spec.freq as u32;
+            SampleQueue {
+                samples: VecDeque::with_capacity(2 * MAX_FILL),
+                last: (0, 0),
+            }
+        }));
+        device.resume();
+
+        Ok(Audio {
+            device: device,
+            sample_rate: sample_rate,
+            volume: 100,
+            muted: false,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Number of stereo frames not yet played
+    pub fn queued(&mut self) -> usize {
+        self.device.lock().samples.len() / 2
+    }
+
+    pub fn has_space(&mut self) -> bool {
+        self.queued() < TARGET_FILL
+    }
+
+    // Sample rate the emulator should generate at right now. It is nudged
+    // slightly up when the queue runs low and down when it fills up, which
+    // keeps the queue around the target without audible pitch changes.
+    pub fn adjusted_rate(&mut self) -> u32 {
+        let fill = cmp::min(self.queued(), 2 * TARGET_FILL) as f64;
+        let target = TARGET_FILL as f64;
+        let ratio = 1.0 + MAX_RATE_DELTA * (target - fill) / target;
+        (self.sample_rate as f64 * ratio) as u32
+    }
+
+    pub fn queue(&mut self, samples: &[i16]) {
+        let volume = if self.muted { 0 } else { self.volume as i32 };
+
+        let mut queue = self.device.lock();
+        for &sample in samples {
+            if queue.samples.len() >= 2 * MAX_FILL {
+                break;
+            }
+            queue.samples.push_back((sample as i32 * volume / 100) as i16);
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn increase_volume(&mut self) {
+        self.volume = cmp::min(self.volume + VOLUME_STEP, 100);
+    }
+
+    pub fn decrease_volume(&mut self) {
+        self.volume = self.volume.saturating_sub(VOLUME_STEP);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+    }
+}
@@ -0,0 +1,304 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::fmt;
+
+use error::Error;
+
+pub const HEADER_END: usize = 0x150;
+
+const TITLE: usize = 0x134;
+const MANUFACTURER_CODE: usize = 0x13F;
+const CGB_FLAG: usize = 0x143;
+const NEW_LICENSEE_CODE: usize = 0x144;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const DESTINATION: usize = 0x14A;
+const OLD_LICENSEE_CODE: usize = 0x14B;
+const VERSION: usize = 0x14C;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CgbSupport {
+    None,
+    Supported,
+    Required,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Destination {
+    Japanese,
+    Overseas,
+}
+
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: String,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub old_licensee_code: u8,
+    pub new_licensee_code: String,
+    pub cartridge_type: u8,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    // Size of the ROM image the header was read from
+    pub file_size: usize,
+    pub destination: Destination,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub header_checksum_valid: bool,
+    pub global_checksum_valid: bool,
+}
+
+fn ascii_string(data: &[u8]) -> String {
+    data.iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| if c >= 0x20 && c < 0x7f { c as char } else { '?' })
+        .collect()
+}
+
+fn is_manufacturer_code(data: &[u8]) -> bool {
+    data.iter().all(|&c| (c >= b'A' && c <= b'Z') || (c >= b'0' && c <= b'9'))
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, Error> {
+        if rom.len() < HEADER_END {
+            return Err(Error::TruncatedRom(rom.len()));
+        }
+
+        let cgb_support = match rom[CGB_FLAG] {
+            0xC0 => CgbSupport::Required,
+            flag if flag & 0x80 != 0 => CgbSupport::Supported,
+            _ => CgbSupport::None
+        };
+
+        // Newer cartridges use the end of the title area for the
+        // manufacturer code and the CGB flag
+        let (title, manufacturer_code) = match cgb_support {
+            CgbSupport::None => (ascii_string(&rom[TITLE..CGB_FLAG+1]), String::new()),
+            _ if is_manufacturer_code(&rom[MANUFACTURER_CODE..CGB_FLAG]) => {
+                (ascii_string(&rom[TITLE..MANUFACTURER_CODE]),
+                 ascii_string(&rom[MANUFACTURER_CODE..CGB_FLAG]))
+            },
+            _ => (ascii_string(&rom[TITLE..CGB_FLAG]), String::new())
+        };
+
+        let rom_size = match rom[ROM_SIZE] {
+            size @ 0x00...0x08 => 32768 << size,
+            0x52 => 72 * 16384,
+            0x53 => 80 * 16384,
+            0x54 => 96 * 16384,
+            size => {
+                return Err(Error::BadHeader(format!("Unknown ROM size {:#04x}", size)))
+            }
+        };
+
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 2048,
+            0x02 => 8192,
+            0x03 => 32768,
+            0x04 => 131072,
+            0x05 => 65536,
+            size => {
+                return Err(Error::BadHeader(format!("Unknown RAM size {:#04x}", size)))
+            }
+        };
+
+        let header_checksum = rom[HEADER_CHECKSUM];
+        let global_checksum = ((rom[GLOBAL_CHECKSUM] as u16) << 8)
+                              | rom[GLOBAL_CHECKSUM+1] as u16;
+
+        Ok(CartridgeHeader {
+            title: title,
+            manufacturer_code: manufacturer_code,
+            cgb_support: cgb_support,
+            sgb_support: rom[SGB_FLAG] == 0x03,
+            old_licensee_code: rom[OLD_LICENSEE_CODE],
+            new_licensee_code: ascii_string(&rom[NEW_LICENSEE_CODE..SGB_FLAG]),
+            cartridge_type: rom[CARTRIDGE_TYPE],
+            rom_size: rom_size,
+            ram_size: ram_size,
+            file_size: rom.len(),
+            destination: if rom[DESTINATION] == 0x00 { Destination::Japanese }
+                         else { Destination::Overseas },
+            version: rom[VERSION],
+            header_checksum: header_checksum,
+            global_checksum: global_checksum,
+            header_checksum_valid: compute_header_checksum(rom) == header_checksum,
+            global_checksum_valid: compute_global_checksum(rom) == global_checksum,
+        })
+    }
+
+    // Problems with the ROM image that don't keep it from running
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !self.header_checksum_valid {
+            warnings.push("Invalid cartridge header checksum".to_string());
+        }
+        if !self.global_checksum_valid {
+            warnings.push("Invalid cartridge global checksum".to_string());
+        }
+        if self.file_size != self.rom_size {
+            warnings.push(format!("ROM file has {} bytes, but the header specifies {} bytes",
+                                  self.file_size, self.rom_size));
+        }
+        warnings
+    }
+}
+
+fn compute_header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE..HEADER_CHECKSUM].iter()
+        .fold(0u8, |acc, &byte| acc.wrapping_sub(byte).wrapping_sub(1))
+}
+
+// Sum of all ROM bytes except for the checksum itself
+fn compute_global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != GLOBAL_CHECKSUM && i != GLOBAL_CHECKSUM+1)
+        .fold(0u16, |acc, (_, &byte)| acc.wrapping_add(byte as u16))
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "Title: {}", self.title));
+        try!(writeln!(f, "Manufacturer: {}", self.manufacturer_code));
+        if self.old_licensee_code == 0x33 {
+            try!(writeln!(f, "Licensee: {}", self.new_licensee_code));
+        } else {
+            try!(writeln!(f, "Licensee: {:#04x}", self.old_licensee_code));
+        }
+        try!(writeln!(f, "Type: {:#04x}, Version: {}", self.cartridge_type, self.version));
+        try!(writeln!(f, "ROM: {} KB, RAM: {} KB", self.rom_size / 1024, self.ram_size / 1024));
+        try!(writeln!(f, "CGB: {:?}, SGB: {}, Destination: {:?}",
+                      self.cgb_support, self.sgb_support, self.destination));
+        write!(f, "Header checksum: {:#04x} ({}), Global checksum: {:#06x} ({})",
+               self.header_checksum,
+               if self.header_checksum_valid { "ok" } else { "invalid" },
+               self.global_checksum,
+               if self.global_checksum_valid { "ok" } else { "invalid" })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use super::{CartridgeHeader, HEADER_CHECKSUM, HEADER_END, RAM_SIZE, ROM_SIZE, TITLE};
+
+    // An empty 32 KB ROM with valid checksums
+    fn create_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        // 25 zero bytes, each subtracting one
+        rom[HEADER_CHECKSUM] = 0xE7;
+        rom[0x14E] = 0x00;
+        rom[0x14F] = 0xE7;
+        rom
+    }
+
+    #[test]
+    fn test_checksums() {
+        let header = CartridgeHeader::parse(&create_rom()).unwrap();
+        assert_eq!(header.header_checksum, 0xE7);
+        assert_eq!(header.global_checksum, 0x00E7);
+        assert!(header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+        assert!(header.warnings().is_empty());
+
+        // Bytes after the header only count towards the global checksum
+        let mut rom = create_rom();
+        rom[0x4000] = 0x01;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.header_checksum_valid);
+        assert!(!header.global_checksum_valid);
+        assert_eq!(header.warnings().len(), 1);
+
+        let mut rom = create_rom();
+        rom[TITLE] = 0x41;
+        rom[0x14E] = 0x01;
+        rom[0x14F] = 0x28;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+
+        rom[HEADER_CHECKSUM] = 0xE7 - 0x41;
+        rom[0x14E] = 0x00;
+        rom[0x14F] = 0xE7;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+    }
+
+    #[test]
+    fn test_rom_size() {
+        let sizes = [(0x00, 32768), (0x01, 65536), (0x05, 1048576), (0x08, 8388608),
+                     (0x52, 1179648), (0x53, 1310720), (0x54, 1572864)];
+        for &(code, size) in sizes.iter() {
+            let mut rom = create_rom();
+            rom[ROM_SIZE] = code;
+            assert_eq!(CartridgeHeader::parse(&rom).unwrap().rom_size, size);
+        }
+
+        // The ROM file doesn't match its header
+        let mut rom = create_rom();
+        rom[ROM_SIZE] = 0x01;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.file_size, 32768);
+        assert!(header.warnings().iter().any(|warning| warning.contains("65536 bytes")));
+
+        for &code in [0x09, 0x51, 0x55, 0xFF].iter() {
+            let mut rom = create_rom();
+            rom[ROM_SIZE] = code;
+            match CartridgeHeader::parse(&rom) {
+                Err(Error::BadHeader(_)) => {},
+                _ => panic!("ROM size {:#04x} was accepted", code)
+            }
+        }
+    }
+
+    #[test]
+    fn test_ram_size() {
+        let sizes = [(0x00, 0), (0x01, 2048), (0x02, 8192), (0x03, 32768),
+                     (0x04, 131072), (0x05, 65536)];
+        for &(code, size) in sizes.iter() {
+            let mut rom = create_rom();
+            rom[RAM_SIZE] = code;
+            assert_eq!(CartridgeHeader::parse(&rom).unwrap().ram_size, size);
+        }
+
+        for &code in [0x06, 0x80, 0xFF].iter() {
+            let mut rom = create_rom();
+            rom[RAM_SIZE] = code;
+            match CartridgeHeader::parse(&rom) {
+                Err(Error::BadHeader(_)) => {},
+                _ => panic!("RAM size {:#04x} was accepted", code)
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        match CartridgeHeader::parse(&create_rom()[..HEADER_END - 1]) {
+            Err(Error::TruncatedRom(len)) => assert_eq!(len, HEADER_END - 1),
+            _ => panic!("truncated ROM was accepted")
+        }
+    }
+}
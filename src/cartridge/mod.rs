@@ -0,0 +1,541 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::cmp;
+
+use error::Error;
+use events;
+use state::{StateReader, StateWriter};
+
+mod header;
+mod rtc;
+
+pub use self::header::{CartridgeHeader, CgbSupport};
+
+const ROM_BANK_SIZE: usize = 16384;
+const RAM_BANK_SIZE: usize = 8192;
+const MBC2_RAM_SIZE: usize = 512;
+
+bitflags! {
+    flags CartridgeFeatures: u32 {
+        const ROM = 1 << 0,
+        const MBC1 = 1 << 1,
+        const MBC2 = 1 << 2,
+        const MBC3 = 1 << 3,
+        const MBC5 = 1 << 4,
+        const RAM = 1 << 5,
+        const MMM01 = 1 << 6,
+        const BATTERY = 1 << 7,
+        const SRAM = 1 << 8,
+        const RUMBLE = 1 << 9,
+        const TIMER = 1 << 10,
+    }
+}
+
+impl CartridgeFeatures {
+    fn from_type_byte(value: u8) -> Result<CartridgeFeatures, Error> {
+        let features = match value {
+            0x00 => ROM,
+            0x01 => ROM | MBC1,
+            0x02 => ROM | MBC1 | RAM,
+            0x03 => ROM | MBC1 | RAM | BATTERY,
+            0x05 => ROM | MBC2,
+            0x06 => ROM | MBC2 | BATTERY,
+            0x08 => ROM | RAM,
+            0x09 => ROM | RAM | BATTERY,
+            0x0B => ROM | MMM01,
+            0x0C => ROM | MMM01 | SRAM,
+            0x0D => ROM | MMM01 | SRAM | BATTERY,
+            0x0F => ROM | MBC3 | TIMER | BATTERY,
+            0x10 => ROM | MBC3 | TIMER | RAM | BATTERY,
+            0x11 => ROM | MBC3,
+            0x12 => ROM | MBC3 | RAM,
+            0x13 => ROM | MBC3 | RAM | BATTERY,
+            0x19 => ROM | MBC5,
+            0x1A => ROM | MBC5 | RAM,
+            0x1B => ROM | MBC5 | RAM | BATTERY,
+            0x1C => ROM | MBC5 | RUMBLE,
+            0x1D => ROM | MBC5 | RUMBLE | SRAM,
+            0x1E => ROM | MBC5 | RUMBLE | SRAM | BATTERY,
+            _    => return Err(Error::UnsupportedMapper(value))
+        };
+        Ok(features)
+    }
+}
+
+#[derive(Debug)]
+enum MBC {
+    None, MBC1, MBC2, MBC3, MBC5
+}
+
+impl MBC {
+    fn from_features(features: CartridgeFeatures) -> Option<MBC> {
+        let mbc = if features.contains(MBC1) {
+            MBC::MBC1
+        } else if features.contains(MBC2) {
+            MBC::MBC2
+        } else if features.contains(MBC3) {
+            MBC::MBC3
+        } else if features.contains(MBC5) {
+            MBC::MBC5
+        } else if features.contains(MMM01) {
+            return None;
+        } else {
+            MBC::None
+        };
+        Some(mbc)
+    }
+}
+
+pub struct Cartridge {
+    header: CartridgeHeader,
+    rom: Box<[u8]>,
+    rom_bank: u16,
+    ram: Vec<u8>,
+    ram_bank: u8,
+    ram_mode: bool,
+    ram_enabled: bool,
+    mbc: MBC,
+    rtc: rtc::Rtc,
+
+    has_rumble: bool,
+    rumble: bool,
+    rumble_changed: bool,
+
+    has_battery: bool,
+    has_rtc: bool,
+    ram_modified: bool,
+}
+
+impl Cartridge {
+    pub fn new(rom: Box<[u8]>) -> Result<Cartridge, Error> {
+        let header = try!(CartridgeHeader::parse(&rom));
+        let features = try!(CartridgeFeatures::from_type_byte(header.cartridge_type));
+        let mbc = match MBC::from_features(features) {
+            Some(mbc) => mbc,
+            None => return Err(Error::UnsupportedMapper(header.cartridge_type))
+        };
+
+        // Banks missing from the file read as 0xFF
+        let mut rom_data = vec![0xff; header.rom_size];
+        let len = cmp::min(rom.len(), header.rom_size);
+        rom_data[..len].copy_from_slice(&rom[..len]);
+
+        // MBC2 RAM is built into the controller and not listed in the header
+        let ram_size = match mbc {
+            MBC::MBC2 => MBC2_RAM_SIZE,
+            _ => header.ram_size
+        };
+
+        Ok(Cartridge {
+            header: header,
+            rom: rom_data.into_boxed_slice(),
+            rom_bank: 1,
+            ram: vec![0; ram_size],
+            ram_bank: 0,
+            ram_mode: false,
+            ram_enabled: false,
+            mbc: mbc,
+            rtc: rtc::Rtc::new(),
+
+            has_rumble: features.contains(RUMBLE),
+            rumble: false,
+            rumble_changed: false,
+
+            has_battery: features.contains(BATTERY),
+            has_rtc: features.contains(TIMER),
+            ram_modified: false,
+        })
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.has_battery
+    }
+
+    pub fn take_ram_modified(&mut self) -> bool {
+        let modified = self.ram_modified;
+        self.ram_modified = false;
+        modified
+    }
+
+    // Battery-backed state as stored in .sav files: the raw RAM contents,
+    // followed by the RTC footer on carts with a clock
+    pub fn save_data(&mut self) -> Vec<u8> {
+        let mut buf = self.ram.clone();
+        if self.has_rtc {
+            self.rtc.save(&mut buf);
+        }
+        buf
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.ram_mode);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u32(self.ram.len() as u32);
+        writer.write_bytes(&self.ram);
+        self.rtc.save_state(writer);
+        writer.write_bool(self.rumble);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.rom_bank = try!(reader.read_u16());
+        self.ram_bank = try!(reader.read_u8());
+        self.ram_mode = try!(reader.read_bool());
+        self.ram_enabled = try!(reader.read_bool());
+
+        let ram_len = try!(reader.read_u32()) as usize;
+        if ram_len != self.ram.len() {
+            return Err(Error::BadState(format!("expected {} bytes of cartridge RAM, got {}",
+                                               self.ram.len(), ram_len)));
+        }
+        let mut ram = vec![0; ram_len];
+        try!(reader.read_bytes(&mut ram));
+        // Rewinding loads states all the time, the .sav file only needs to
+        // be written again if the battery RAM actually changed
+        if ram[..] != self.ram[..] {
+            self.ram.copy_from_slice(&ram);
+            self.ram_modified = true;
+        }
+
+        try!(self.rtc.load_state(reader));
+
+        let rumble = try!(reader.read_bool());
+        self.rumble_changed |= rumble != self.rumble;
+        self.rumble = rumble;
+        Ok(())
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let len = cmp::min(self.ram.len(), data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+
+        let footer = &data[len..];
+        if self.has_rtc && rtc::is_footer_size(footer.len()) {
+            self.rtc.load(footer);
+        }
+    }
+
+    pub fn set_rtc_host_sync(&mut self, enabled: bool) {
+        self.rtc.set_host_sync(enabled);
+    }
+
+    pub fn tick(&mut self, cycles: u8) -> events::Events {
+        // Only MBC3 cartridges with a timer have a clock crystal
+        if self.has_rtc {
+            self.rtc.tick(cycles);
+        }
+
+        if self.rumble_changed {
+            self.rumble_changed = false;
+            events::RUMBLE
+        } else {
+            events::Events::empty()
+        }
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    pub fn read_rom_bank0(&self, addr: u16) -> u8 {
+        self.rom[addr as usize]
+    }
+
+    pub fn read_rom_bank1(&self, addr: u16) -> u8 {
+        match self.mbc {
+            MBC::None => self.rom[ROM_BANK_SIZE + addr as usize],
+            // Bank numbers wrap around the ROM size. Only MBC5 can map bank 0 here.
+            _ => {
+                let num_banks = self.rom.len() / ROM_BANK_SIZE;
+                let bank = self.rom_bank as usize % num_banks;
+                self.rom[ROM_BANK_SIZE * bank + addr as usize]
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match self.mbc {
+            MBC::None => {},
+            MBC::MBC1 => {
+                match addr {
+                    0x0000...0x1FFF if value == 0x0A => self.ram_enabled = true,
+                    0x0000...0x1FFF => self.ram_enabled = false,
+                    0x2000...0x3FFF if value == 0 => {
+                        self.rom_bank &= 0b11100000;
+                        self.rom_bank |= 1;
+                    },
+                    0x2000...0x3FFF => {
+                        self.rom_bank &= 0b11100000;
+                        self.rom_bank |= (value & 0b11111) as u16;
+                    },
+                    0x4000...0x5FFF if !self.ram_mode => {
+                        self.rom_bank &= 0b10011111;
+                        self.rom_bank |= ((value & 0b11) << 5) as u16;
+                    },
+                    0x4000...0x5FFF => self.ram_bank = value & 0b11,
+                    0x6000...0x7FFF => self.ram_mode = value & 0x1 != 0,
+                    _ => {}
+                }
+            },
+            MBC::MBC2 => {
+                match addr {
+                    // Address bit 8 selects between RAM enable and ROM bank
+                    0x0000...0x3FFF if addr & 0x100 == 0 => {
+                        self.ram_enabled = value & 0x0F == 0x0A;
+                    },
+                    0x0000...0x3FFF => self.rom_bank = cmp::max(value & 0x0F, 1) as u16,
+                    _ => {}
+                }
+            },
+            MBC::MBC3 => {
+                match addr {
+                    0x0000...0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                    0x2000...0x3FFF => self.rom_bank = cmp::max(value & 0x7F, 1) as u16,
+                    // RAM banks 0x00-0x03, RTC registers 0x08-0x0C
+                    0x4000...0x5FFF => self.ram_bank = value,
+                    0x6000...0x7FFF if self.has_rtc => self.rtc.write_latch(value),
+                    _ => {}
+                }
+            },
+            MBC::MBC5 => {
+                match addr {
+                    0x0000...0x1FFF => self.ram_enabled = value == 0x0A,
+                    // 9-bit ROM bank number, split over two registers
+                    0x2000...0x2FFF => {
+                        self.rom_bank = (self.rom_bank & 0x100) | value as u16;
+                    },
+                    0x3000...0x3FFF => {
+                        self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x1) << 8);
+                    },
+                    // On rumble carts, bit 3 drives the motor instead of RAM banking
+                    0x4000...0x5FFF if self.has_rumble => {
+                        self.ram_bank = value & 0x07;
+                        let rumble = value & 0x08 != 0;
+                        self.rumble_changed |= rumble != self.rumble;
+                        self.rumble = rumble;
+                    },
+                    0x4000...0x5FFF => self.ram_bank = value & 0x0F,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        match self.mbc {
+            MBC::MBC2 => self.read_mbc2_ram(addr),
+            MBC::MBC3 => self.read_mbc3_ram(addr),
+            _ if self.ram_enabled && self.ram.len() > 0 => {
+                let ofs = RAM_BANK_SIZE * self.ram_bank as usize + addr as usize;
+                self.ram[ofs % self.ram.len()]
+            },
+            _ => 0x00
+        }
+    }
+
+    pub fn write_ram(&mut self, addr: u16, value: u8) {
+        match self.mbc {
+            MBC::MBC2 => self.write_mbc2_ram(addr, value),
+            MBC::MBC3 => self.write_mbc3_ram(addr, value),
+            _ if self.ram_enabled && self.ram.len() > 0 => {
+                let ofs = RAM_BANK_SIZE * self.ram_bank as usize + addr as usize;
+                let len = self.ram.len();
+                self.ram[ofs % len] = value;
+                self.ram_modified = true;
+            },
+            _ => {}
+        }
+    }
+
+    // MBC2 has 512 half-byte cells, mirrored across the whole ERAM area
+    fn read_mbc2_ram(&self, addr: u16) -> u8 {
+        if self.ram_enabled {
+            self.ram[addr as usize % MBC2_RAM_SIZE] | 0xF0
+        } else {
+            0xff
+        }
+    }
+
+    fn write_mbc2_ram(&mut self, addr: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[addr as usize % MBC2_RAM_SIZE] = value & 0x0F;
+            self.ram_modified = true;
+        }
+    }
+
+    fn read_mbc3_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+
+        match self.ram_bank {
+            0x00...0x03 if self.ram.len() > 0 => {
+                let ofs = RAM_BANK_SIZE * self.ram_bank as usize + addr as usize;
+                self.ram[ofs % self.ram.len()]
+            },
+            0x08...0x0C if self.has_rtc => self.rtc.read(self.ram_bank),
+            _ => 0xff
+        }
+    }
+
+    fn write_mbc3_ram(&mut self, addr: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        match self.ram_bank {
+            0x00...0x03 if self.ram.len() > 0 => {
+                let ofs = RAM_BANK_SIZE * self.ram_bank as usize + addr as usize;
+                let len = self.ram.len();
+                self.ram[ofs % len] = value;
+                self.ram_modified = true;
+            },
+            // Clock writes alone don't need the save file to be rewritten
+            0x08...0x0C if self.has_rtc => self.rtc.write(self.ram_bank, value),
+            _ => {}
+        }
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::{Cartridge, ROM_BANK_SIZE};
+
+    // Every ROM bank starts with its own 16-bit bank number
+    fn create_cartridge(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Cartridge {
+        let mut rom = vec![0; (32768 << rom_size) as usize];
+        for (bank, data) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            data[0] = bank as u8;
+            data[1] = (bank >> 8) as u8;
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = ram_size;
+        Cartridge::new(rom.into_boxed_slice()).unwrap()
+    }
+
+    fn rom_bank(cartridge: &Cartridge) -> usize {
+        cartridge.read_rom_bank1(0) as usize | (cartridge.read_rom_bank1(1) as usize) << 8
+    }
+
+    #[test]
+    fn test_mbc3_rom_bank() {
+        // 2 MB, 128 banks
+        let mut cartridge = create_cartridge(0x11, 0x06, 0x00);
+        assert_eq!(rom_bank(&cartridge), 1);
+
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(rom_bank(&cartridge), 1);
+        cartridge.write(0x3FFF, 0x7F);
+        assert_eq!(rom_bank(&cartridge), 0x7F);
+        cartridge.write(0x2000, 0x85);
+        assert_eq!(rom_bank(&cartridge), 0x05);
+        cartridge.write(0x2000, 0x80);
+        assert_eq!(rom_bank(&cartridge), 1);
+    }
+
+    #[test]
+    fn test_ram_modified() {
+        // MBC3 with timer, RAM and battery
+        let mut cartridge = create_cartridge(0x10, 0x00, 0x03);
+        cartridge.write_ram(0x0000, 0x12);
+        assert!(!cartridge.take_ram_modified());
+
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x08);
+        cartridge.write_ram(0x0000, 0x12);
+        assert!(!cartridge.take_ram_modified());
+
+        cartridge.write(0x4000, 0x01);
+        cartridge.write_ram(0x0000, 0x12);
+        assert!(cartridge.take_ram_modified());
+        assert!(!cartridge.take_ram_modified());
+
+        // MBC1 without RAM
+        let mut cartridge = create_cartridge(0x01, 0x00, 0x00);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write_ram(0x0000, 0x12);
+        assert!(!cartridge.take_ram_modified());
+    }
+
+    #[test]
+    fn test_mbc5_rom_bank() {
+        // 8 MB, 512 banks
+        let mut cartridge = create_cartridge(0x19, 0x08, 0x00);
+        assert_eq!(rom_bank(&cartridge), 1);
+
+        // Unlike on the other mappers, bank 0 can be mapped to 0x4000
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(rom_bank(&cartridge), 0);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(rom_bank(&cartridge), 0x100);
+        cartridge.write(0x2FFF, 0xFF);
+        assert_eq!(rom_bank(&cartridge), 0x1FF);
+        cartridge.write(0x3FFF, 0xFE);
+        assert_eq!(rom_bank(&cartridge), 0x0FF);
+    }
+
+    #[test]
+    fn test_mbc1_rom_bank() {
+        // 2 MB, 128 banks
+        let mut cartridge = create_cartridge(0x01, 0x06, 0x00);
+        cartridge.write(0x2000, 0x1F);
+        assert_eq!(rom_bank(&cartridge), 0x1F);
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(rom_bank(&cartridge), 0x01);
+
+        // Banks 0x20, 0x40 and 0x60 can't be selected and map to the next one
+        for &hi in [1, 2, 3].iter() {
+            cartridge.write(0x4000, hi);
+            cartridge.write(0x2000, 0x00);
+            assert_eq!(rom_bank(&cartridge), ((hi as usize) << 5) + 1);
+            cartridge.write(0x3FFF, 0x05);
+            assert_eq!(rom_bank(&cartridge), ((hi as usize) << 5) + 5);
+        }
+    }
+
+    #[test]
+    fn test_mbc1_ram_banking() {
+        // 2 MB ROM, 32 KB RAM
+        let mut cartridge = create_cartridge(0x03, 0x06, 0x03);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x2000, 0x05);
+
+        // In ROM banking mode, only RAM bank 0 is accessible
+        cartridge.write(0x4000, 0x02);
+        assert_eq!(rom_bank(&cartridge), 0x45);
+        cartridge.write_ram(0x0000, 0x11);
+
+        cartridge.write(0x6000, 0x01);
+        cartridge.write(0x4000, 0x02);
+        assert_eq!(rom_bank(&cartridge), 0x45);
+        assert_eq!(cartridge.read_ram(0x0000), 0x00);
+        cartridge.write_ram(0x0000, 0x22);
+
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read_ram(0x0000), 0x11);
+        cartridge.write(0x4000, 0x02);
+        assert_eq!(cartridge.read_ram(0x0000), 0x22);
+
+        // RAM is only accessible while enabled
+        cartridge.write(0x0000, 0x00);
+        assert_eq!(cartridge.read_ram(0x0000), 0x00);
+    }
+}
@@ -0,0 +1,396 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::Error;
+use state::{StateReader, StateWriter};
+
+// The RTC crystal runs at 32768 Hz, but we only track full seconds
+const CYCLES_PER_SECOND: u64 = 4194304 / 4;
+
+const DAY_HI: u8 = 1 << 0;
+const HALT: u8 = 1 << 6;
+const DAY_CARRY: u8 = 1 << 7;
+
+// Save file footer as used by VBA-M and BGB: current and latched registers
+// as 32-bit values followed by a 64-bit UNIX timestamp (older files use 32 bits)
+const FOOTER_SIZE: usize = 48;
+const FOOTER_SIZE_SHORT: usize = 44;
+
+#[derive(Copy, Clone, Debug)]
+struct RtcRegs {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day_lo: u8,
+    day_hi: u8,
+}
+
+impl RtcRegs {
+    fn new() -> RtcRegs {
+        RtcRegs {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            day_lo: 0,
+            day_hi: 0,
+        }
+    }
+
+    fn days(&self) -> u64 {
+        (((self.day_hi & DAY_HI) as u64) << 8) | self.day_lo as u64
+    }
+
+    fn save(&self, buf: &mut Vec<u8>) {
+        let regs = [self.seconds, self.minutes, self.hours, self.day_lo, self.day_hi];
+        for &reg in regs.iter() {
+            write_le(buf, reg as u64, 4);
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&[self.seconds, self.minutes, self.hours,
+                             self.day_lo, self.day_hi]);
+    }
+
+    fn load_state(reader: &mut StateReader) -> Result<RtcRegs, Error> {
+        let mut data = [0; 5];
+        try!(reader.read_bytes(&mut data));
+        Ok(RtcRegs {
+            seconds: data[0] & 0x3f,
+            minutes: data[1] & 0x3f,
+            hours: data[2] & 0x1f,
+            day_lo: data[3],
+            day_hi: data[4] & (DAY_CARRY | HALT | DAY_HI),
+        })
+    }
+
+    fn load(data: &[u8]) -> RtcRegs {
+        RtcRegs {
+            seconds: data[0] & 0x3f,
+            minutes: data[4] & 0x3f,
+            hours: data[8] & 0x1f,
+            day_lo: data[12],
+            day_hi: data[16] & (DAY_CARRY | HALT | DAY_HI),
+        }
+    }
+}
+
+fn write_le(buf: &mut Vec<u8>, value: u64, len: usize) {
+    for i in 0..len {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn read_le(data: &[u8]) -> u64 {
+    data.iter().rev().fold(0, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+pub fn is_footer_size(len: usize) -> bool {
+    len == FOOTER_SIZE || len == FOOTER_SIZE_SHORT
+}
+
+pub struct Rtc {
+    regs: RtcRegs,
+    latched: RtcRegs,
+    latch_value: u8,
+    clock: u64,
+
+    host_sync: bool,
+    last_sync: SystemTime,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            regs: RtcRegs::new(),
+            latched: RtcRegs::new(),
+            latch_value: 0xff,
+            clock: 0,
+
+            host_sync: false,
+            last_sync: SystemTime::now(),
+        }
+    }
+
+    pub fn set_host_sync(&mut self, enabled: bool) {
+        self.host_sync = enabled;
+        self.last_sync = SystemTime::now();
+        self.clock = 0;
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        if self.host_sync || self.regs.day_hi & HALT != 0 {
+            return;
+        }
+
+        self.clock += cycles as u64;
+        if self.clock >= CYCLES_PER_SECOND {
+            self.clock -= CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    fn sync_host(&mut self) {
+        if !self.host_sync {
+            return;
+        }
+
+        // Only whole seconds are consumed, the remainder carries over
+        let now = SystemTime::now();
+        if let Ok(elapsed) = now.duration_since(self.last_sync) {
+            let seconds = elapsed.as_secs();
+            self.advance(seconds);
+            self.last_sync = self.last_sync + Duration::from_secs(seconds);
+        } else {
+            // Host clock went backwards
+            self.last_sync = now;
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if seconds == 0 || self.regs.day_hi & HALT != 0 {
+            return;
+        }
+
+        let mut total = self.regs.seconds as u64 + seconds;
+        self.regs.seconds = (total % 60) as u8;
+        total = total / 60 + self.regs.minutes as u64;
+        self.regs.minutes = (total % 60) as u8;
+        total = total / 60 + self.regs.hours as u64;
+        self.regs.hours = (total % 24) as u8;
+        total = total / 24 + self.regs.days();
+
+        if total > 0x1ff {
+            self.regs.day_hi |= DAY_CARRY;
+        }
+        self.regs.day_lo = total as u8;
+        self.regs.day_hi = (self.regs.day_hi & !DAY_HI) | ((total >> 8) as u8 & DAY_HI);
+    }
+
+    pub fn save(&mut self, buf: &mut Vec<u8>) {
+        self.sync_host();
+        self.regs.save(buf);
+        self.latched.save(buf);
+
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0
+        };
+        write_le(buf, timestamp, 8);
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        if !is_footer_size(data.len()) {
+            return;
+        }
+
+        self.regs = RtcRegs::load(&data[0..20]);
+        self.latched = RtcRegs::load(&data[20..40]);
+        self.clock = 0;
+        self.last_sync = SystemTime::now();
+
+        // The clock keeps running while the emulator is not
+        let timestamp = UNIX_EPOCH + Duration::from_secs(read_le(&data[40..]));
+        if let Ok(elapsed) = self.last_sync.duration_since(timestamp) {
+            self.advance(elapsed.as_secs());
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.regs.save_state(writer);
+        self.latched.save_state(writer);
+        writer.write_u8(self.latch_value);
+        writer.write_u64(self.clock);
+
+        let timestamp = match self.last_sync.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0
+        };
+        writer.write_u64(timestamp);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.regs = try!(RtcRegs::load_state(reader));
+        self.latched = try!(RtcRegs::load_state(reader));
+        self.latch_value = try!(reader.read_u8());
+        self.clock = try!(reader.read_u64());
+
+        // When following the host clock, catch up on the time since the state
+        // was made on the next access
+        let timestamp = try!(reader.read_u64());
+        if self.host_sync {
+            self.last_sync = UNIX_EPOCH + Duration::from_secs(timestamp);
+        }
+        Ok(())
+    }
+
+    // 0x6000-0x7FFF: Writing 0x00 followed by 0x01 latches the clock
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_value == 0x00 && value == 0x01 {
+            self.sync_host();
+            self.latched = self.regs;
+        }
+        self.latch_value = value;
+    }
+
+    // RAM bank 0x08-0x0C
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.latched.seconds,
+            0x09 => self.latched.minutes,
+            0x0A => self.latched.hours,
+            0x0B => self.latched.day_lo,
+            0x0C => self.latched.day_hi,
+            _ => 0xff
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, value: u8) {
+        // Account for the time passed before the registers change,
+        // especially when the clock is being halted or resumed
+        self.sync_host();
+
+        match reg {
+            0x08 => {
+                self.regs.seconds = value & 0x3f;
+                self.clock = 0;
+            },
+            0x09 => self.regs.minutes = value & 0x3f,
+            0x0A => self.regs.hours = value & 0x1f,
+            0x0B => self.regs.day_lo = value,
+            0x0C => self.regs.day_hi = value & (DAY_CARRY | HALT | DAY_HI),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{Rtc, write_le, CYCLES_PER_SECOND, DAY_CARRY, DAY_HI, HALT};
+
+    fn tick_seconds(rtc: &mut Rtc, seconds: u64) {
+        for _ in 0..seconds * CYCLES_PER_SECOND / 128 {
+            rtc.tick(128);
+        }
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    fn footer(seconds: u8, day_hi: u8, timestamp: u64, timestamp_len: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for _ in 0..2 {
+            for &reg in [seconds, 0, 0, 0, day_hi].iter() {
+                write_le(&mut buf, reg as u64, 4);
+            }
+        }
+        write_le(&mut buf, timestamp, timestamp_len);
+        buf
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn test_latch() {
+        let mut rtc = Rtc::new();
+        tick_seconds(&mut rtc, 3);
+        assert_eq!(rtc.read(0x08), 0);
+
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 3);
+
+        // Only a 0x00 -> 0x01 transition latches
+        tick_seconds(&mut rtc, 2);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 3);
+        rtc.write_latch(0x00);
+        assert_eq!(rtc.read(0x08), 3);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 5);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0C, HALT);
+        tick_seconds(&mut rtc, 2);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+
+        rtc.write(0x0C, 0);
+        tick_seconds(&mut rtc, 2);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 2);
+    }
+
+    #[test]
+    fn test_day_carry() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0A, 23);
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, DAY_HI);
+        assert_eq!(rtc.regs.days(), 0x1FF);
+
+        tick_seconds(&mut rtc, 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x0A), 0);
+        assert_eq!(rtc.read(0x0B), 0);
+        assert_eq!(rtc.read(0x0C), DAY_CARRY);
+
+        // The carry stays set until the game clears it
+        rtc.advance(24 * 60 * 60);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0B), 1);
+        assert_eq!(rtc.read(0x0C), DAY_CARRY);
+
+        rtc.write(0x0C, 0);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0C), 0);
+    }
+
+    #[test]
+    fn test_load_footer() {
+        // A halted clock doesn't catch up on the time since the save
+        let mut rtc = Rtc::new();
+        rtc.load(&footer(42, HALT, 0, 8));
+        assert_eq!(rtc.read(0x08), 42);
+        assert_eq!(rtc.regs.seconds, 42);
+        assert_eq!(rtc.regs.day_hi, HALT);
+
+        // The older footer has a 32-bit timestamp, an hour passed since
+        let mut rtc = Rtc::new();
+        rtc.load(&footer(0, 0, now() - 3600, 4));
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.regs.hours, 1);
+        assert_eq!(rtc.regs.minutes, 0);
+
+        let mut rtc = Rtc::new();
+        let mut data = footer(42, HALT, 0, 8);
+        data.pop();
+        rtc.load(&data);
+        assert_eq!(rtc.regs.seconds, 0);
+    }
+}
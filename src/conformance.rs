@@ -0,0 +1,187 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use events;
+use gameboy::{Gameboy, CYCLES_PER_FRAME};
+use instructions::Reg8;
+
+// Runs the Blargg and Mooneye test ROMs found below the directory in this
+// variable and prints a table with the results. The ROMs aren't distributed
+// with the emulator, so the test does nothing without it.
+const ROM_DIR_VAR: &'static str = "GBEMU_TEST_ROMS";
+
+// Emulated time after which a ROM counts as hung. The complete cpu_instrs
+// ROM needs close to a minute.
+const TIMEOUT_FRAMES: u64 = 120 * 60;
+
+// Blargg's ROMs write their result to cartridge RAM after this signature
+const BLARGG_SIGNATURE_ADDR: u16 = 0xa001;
+const BLARGG_SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const BLARGG_STATUS_ADDR: u16 = 0xa000;
+const BLARGG_TEXT_ADDR: u16 = 0xa004;
+const BLARGG_RUNNING: u8 = 0x80;
+
+// Mooneye's ROMs load these into B, C, D, E, H and L on success
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Timeout,
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir)
+        .unwrap_or_else(|why| panic!("Could not read {}: {}", dir.display(), why));
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().map_or(false, |ext| ext == "gb" || ext == "gbc") {
+            roms.push(path);
+        }
+    }
+}
+
+fn has_blargg_signature(gameboy: &Gameboy) -> bool {
+    BLARGG_SIGNATURE.iter().enumerate().all(|(i, &byte)| {
+        gameboy.read_mem(BLARGG_SIGNATURE_ADDR + i as u16) == byte
+    })
+}
+
+fn blargg_memory_result(gameboy: &Gameboy) -> Option<Outcome> {
+    if !has_blargg_signature(gameboy) {
+        return None;
+    }
+
+    match gameboy.read_mem(BLARGG_STATUS_ADDR) {
+        BLARGG_RUNNING => None,
+        0 => Some(Outcome::Pass),
+        status => {
+            let mut text = Vec::new();
+            let mut addr = BLARGG_TEXT_ADDR;
+            while addr < 0xc000 && gameboy.read_mem(addr) != 0 {
+                text.push(gameboy.read_mem(addr));
+                addr += 1;
+            }
+            let text = String::from_utf8_lossy(&text);
+            Some(Outcome::Fail(format!("status {:#04x}: {}", status, text.trim())))
+        }
+    }
+}
+
+fn blargg_serial_result(serial: &[u8]) -> Option<Outcome> {
+    let text = String::from_utf8_lossy(serial);
+    if text.contains("Passed") {
+        Some(Outcome::Pass)
+    } else if text.contains("Failed") {
+        let last_line = text.trim().lines().last().unwrap_or("");
+        Some(Outcome::Fail(String::from(last_line.trim())))
+    } else {
+        None
+    }
+}
+
+fn mooneye_result(gameboy: &Gameboy) -> Outcome {
+    let regs = [Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L];
+    let values: Vec<u8> = regs.iter().map(|&reg| gameboy.read_reg8(reg)).collect();
+    if values[..] == MOONEYE_PASS[..] {
+        Outcome::Pass
+    } else {
+        let values: Vec<String> = values.iter().map(|value| format!("{:02x}", value)).collect();
+        Outcome::Fail(format!("registers {}", values.join(" ")))
+    }
+}
+
+fn run_rom(path: &Path) -> Outcome {
+    let mut rom = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut rom))
+        .unwrap_or_else(|why| panic!("Could not read {}: {}", path.display(), why));
+
+    let mut gameboy = match Gameboy::new(None, rom.into_boxed_slice()) {
+        Ok(gameboy) => gameboy,
+        Err(why) => return Outcome::Fail(format!("{}", why))
+    };
+
+    let mut serial = Vec::new();
+    for frame in 1..TIMEOUT_FRAMES+1 {
+        let target = frame * CYCLES_PER_FRAME;
+        while gameboy.total_cycles() < target {
+            let (_, events) = gameboy.simulate(target);
+            if events.contains(events::DEBUG_BREAK) {
+                // Blargg's ROMs execute LD B,B while testing it, only a ROM
+                // that hasn't shown any sign of being one of them is done
+                serial.extend(gameboy.take_serial_output());
+                if serial.is_empty() && !has_blargg_signature(&gameboy) {
+                    return mooneye_result(&gameboy);
+                }
+            }
+            if events.contains(events::LOCKED) {
+                return Outcome::Fail(format!("locked up at {:#06x}", gameboy.pc()));
+            }
+        }
+
+        serial.extend(gameboy.take_serial_output());
+        if let Some(outcome) = blargg_serial_result(&serial) {
+            return outcome;
+        }
+        if let Some(outcome) = blargg_memory_result(&gameboy) {
+            return outcome;
+        }
+    }
+    Outcome::Timeout
+}
+
+#[test]
+fn test_roms() {
+    let dir = match env::var(ROM_DIR_VAR) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            println!("{} is not set, skipping the test ROMs", ROM_DIR_VAR);
+            return;
+        }
+    };
+
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    roms.sort();
+
+    let mut failed = 0;
+    println!("{:<60} Result", "ROM");
+    for path in &roms {
+        let name = path.strip_prefix(&dir).unwrap_or(path).display().to_string();
+        let result = match run_rom(path) {
+            Outcome::Pass => String::from("pass"),
+            Outcome::Fail(why) => {
+                failed += 1;
+                format!("FAIL ({})", why)
+            },
+            Outcome::Timeout => {
+                failed += 1;
+                String::from("FAIL (timeout)")
+            }
+        };
+        println!("{:<60} {}", name, result);
+    }
+    println!("{} of {} test ROMs passed", roms.len() - failed, roms.len());
+
+    assert!(failed == 0, "{} test ROMs failed", failed);
+}
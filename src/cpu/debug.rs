@@ -57,6 +57,10 @@ impl<B> super::Cpu<B> where B: Bus {
 	}
 
 	pub fn single_step(&mut self, db: &mut DebugInfo) {
+		if self.is_locked() {
+			return;
+		}
+
 		let instr = self.fetch_instr();
 		db.cur_instr = instr;
 		self.execute_instr(instr);
@@ -83,10 +87,19 @@ impl<B> super::Cpu<B> where B: Bus {
 			self.handle_updates();
             self.handle_interrupts();
             self.handle_updates();
+
+			if self.is_locked() {
+				break;
+			}
 		}
 	}
 
 	pub fn read_mem(&self, addr: u16) -> u8 {
 		self.bus.read(addr)
 	}
+
+	#[cfg(all(test, feature = "conformance"))]
+	pub fn read_reg8(&self, reg: instructions::Reg8) -> u8 {
+		self.regs.read8(reg)
+	}
 }
\ No newline at end of file
@@ -19,9 +19,14 @@ use super::registers::{ZERO, CARRY};
 impl<B> super::Cpu<B> where B: Bus {
     #[inline(always)]
     pub fn push(&mut self, value: u16) {
-        self.regs.sp = self.regs.sp.wrapping_sub(2);
+        // SP is decremented in an extra cycle, then the high byte is written first
+        self.tick();
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
         let addr = self.regs.sp;
-        self.write_word(addr, value);
+        self.write_byte(addr, (value >> 8) as u8);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        let addr = self.regs.sp;
+        self.write_byte(addr, value as u8);
     }
 
     #[inline(always)]
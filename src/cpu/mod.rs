@@ -16,7 +16,9 @@
 use std::fmt;
 
 use hardware::Bus;
+use error::Error;
 use events;
+use state::{StateReader, StateWriter};
 use instructions;
 use instructions::{Instr, Op, Condition, Addr, Reg8, Reg16};
 use mem_map;
@@ -27,9 +29,21 @@ pub mod debug;
 #[cfg(test)]
 mod test;
 
-use self::registers::{Registers, SUB, ZERO, CARRY, HCARRY};
+use self::registers::{Registers, Flags, SUB, ZERO, CARRY, HCARRY};
+
+#[cfg_attr(test, derive(Debug))]
+#[derive(Eq, PartialEq)]
+enum CpuState {
+    Running,
+    Halted,
+    // Low power mode after STOP, until a joypad line goes low
+    Stopped,
+    // Hard lock after an illegal opcode, only an emulator reset helps
+    Locked,
+}
 
-#[cfg_attr(test, derive(Eq, PartialEq, Debug))]
+#[cfg_attr(test, derive(Debug))]
+#[derive(Eq, PartialEq)]
 enum IntEnable {
     No,
     Pending,
@@ -39,12 +53,16 @@ enum IntEnable {
 pub struct Cpu<B: Bus> {
     regs: Registers,
 
-    halted: bool,
+    state: CpuState,
+    // Set when HALT failed to increment PC after the following opcode
+    halt_bug: bool,
     int_flag: bool,
     int_enable: IntEnable,
 
-    total_cycles: u64,
     last_cycles: u8,
+    // Cycles of the current instruction that were already passed to the bus
+    ticks: u8,
+    tick_events: events::Events,
 
     bus: B,
 }
@@ -54,23 +72,55 @@ impl<B> Cpu<B> where B: Bus {
         Cpu {
             regs: Registers::new(),
 
-            halted: false,
+            state: CpuState::Running,
+            halt_bug: false,
             int_flag: false,
             int_enable: IntEnable::No,
 
-            total_cycles: 0,
             last_cycles: 0,
+            ticks: 0,
+            tick_events: events::Events::empty(),
 
             bus: bus,
         }
     }
 
+    pub fn skip_bios(&mut self, cgb: bool) {
+        self.regs = if cgb {
+            Registers::post_boot_cgb()
+        } else {
+            Registers::post_boot()
+        };
+    }
+
     pub fn step(&mut self) -> events::Events {
-        if self.halted {
-            if self.bus.has_irq() {
-                self.halted = false;
-                return events::Events::empty();
-            } else {
+        // The CPU doesn't run while a VRAM DMA transfer is copying
+        let stall = self.bus.stall_cycles();
+        if stall != 0 {
+            self.last_cycles = stall;
+            return self.handle_updates();
+        }
+
+        match self.state {
+            CpuState::Running => {},
+            CpuState::Halted => {
+                // Waking up takes one more cycle. Without IME, execution
+                // continues after HALT without servicing the interrupt.
+                if self.bus.has_irq() {
+                    self.state = CpuState::Running;
+                }
+                self.last_cycles = 1;
+                return self.handle_updates();
+            },
+            CpuState::Stopped => {
+                if self.bus.is_stopped() {
+                    self.last_cycles = 1;
+                    return self.handle_updates();
+                }
+                self.state = CpuState::Running;
+            },
+            CpuState::Locked => {
+                // The peripherals keep running, but the CPU never fetches again
                 self.last_cycles = 1;
                 return self.handle_updates();
             }
@@ -94,33 +144,74 @@ impl<B> Cpu<B> where B: Bus {
         self.execute_instr(instr);
         
         events = events | self.handle_updates();
+        if self.state == CpuState::Locked {
+            events.insert(events::LOCKED);
+        }
+        #[cfg(all(test, feature = "conformance"))]
+        {
+            if let Op::ld8_rr { dest: Reg8::B, src: Reg8::B } = instr.op {
+                events.insert(events::DEBUG_BREAK);
+            }
+        }
 
         return events;
     }
 
     fn handle_updates(&mut self) -> events::Events {
-        self.total_cycles += self.last_cycles as u64;
-        let mut events = events::Events::empty();
-        if self.last_cycles != 0 {
-            events = self.bus.update(self.last_cycles);
+        // Only the cycles without memory access are left to pass on
+        let cycles = self.last_cycles.saturating_sub(self.ticks);
+        let mut events = self.tick_events;
+        if cycles != 0 {
+            events = events | self.bus.update(cycles);
         }
         self.last_cycles = 0;
+        self.ticks = 0;
+        self.tick_events = events::Events::empty();
         events
     }
 
+    // Every memory access takes one cycle, which passes before the access
+    // happens. This way, the hardware sees the accesses in the exact order.
+    fn tick(&mut self) {
+        self.ticks += 1;
+        let events = self.bus.tick();
+        self.tick_events.insert(events);
+    }
+
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.bus.read(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.tick();
+        self.bus.write(addr, value);
+    }
+
     fn handle_interrupts(&mut self) {
         if self.int_flag && self.bus.has_irq() {
-            if let Some(int) = self.bus.ack_irq() {
-                self.int_flag = false;
+            self.int_flag = false;
 
-                let pc = self.regs.pc;
-                self.push(pc);
-                self.regs.pc = int.isr_addr();
+            // Two wait cycles, then PC is pushed and finally set in the fifth
+            self.tick();
+            self.tick();
 
-                // This might be 3 as well, depending on if the CPU executes 
-                // 2 nop instructions before executing the ISR
-                self.last_cycles = 5;
-            }
+            let pc = self.regs.pc;
+            self.regs.sp = self.regs.sp.wrapping_sub(1);
+            let addr = self.regs.sp;
+            self.write_byte(addr, (pc >> 8) as u8);
+
+            // The vector is only chosen after the high byte is pushed. If that
+            // push overwrote IE and disabled all pending interrupts, the
+            // dispatch is cancelled and the CPU jumps to 0x0000 instead.
+            let int = self.bus.ack_irq();
+
+            self.regs.sp = self.regs.sp.wrapping_sub(1);
+            let addr = self.regs.sp;
+            self.write_byte(addr, pc as u8);
+
+            self.regs.pc = int.map_or(0x0000, |int| int.isr_addr());
+            self.last_cycles = 5;
         }
     }
 
@@ -128,6 +219,11 @@ impl<B> Cpu<B> where B: Bus {
         use super::instructions::Immediate::{None, Imm8, Imm16};
 
         let opcode = self.extract_instr8();
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.regs.pc = self.regs.pc.wrapping_sub(1);
+        }
+
         match instructions::from_opcode(opcode) {
             (Op::ext, None) => {
                 let ext_opcode = self.extract_instr8();
@@ -147,10 +243,24 @@ impl<B> Cpu<B> where B: Bus {
         match instr.op {
             Op::nop => {},
             Op::stop => {
-                // TODO: implement
+                // A prepared CGB speed switch is done by the bus instead of stopping
+                if !self.bus.stop() {
+                    self.state = CpuState::Stopped;
+                }
             },
             Op::halt => {
-                self.halted = true;
+                let has_irq = self.bus.has_irq();
+                if self.int_enable == IntEnable::Enable && has_irq {
+                    // Right after EI, the pending interrupt is serviced at
+                    // once, but returns to the HALT instead of behind it
+                    self.regs.pc = self.regs.pc.wrapping_sub(1);
+                } else if !self.int_flag && has_irq {
+                    // With a pending interrupt, HALT doesn't halt at all,
+                    // but the byte after it is read twice
+                    self.halt_bug = true;
+                } else {
+                    self.state = CpuState::Halted;
+                }
             },
             Op::di => {
                 self.int_enable = IntEnable::No;
@@ -186,7 +296,7 @@ impl<B> Cpu<B> where B: Bus {
 
             Op::st8_ind_imm => {
                 let addr = self.regs.read16(Reg16::HL);
-                self.bus.write(addr, instr.imm.imm8());
+                self.write_byte(addr, instr.imm.imm8());
             },
             Op::st8_ind { dest, src } => {
                 let value = self.regs.read8(src);
@@ -209,23 +319,23 @@ impl<B> Cpu<B> where B: Bus {
             /* I/O instructions */
             Op::in8_reg => {
                 let ofs = self.regs.read8(Reg8::C) as u16;
-                let value = self.bus.read(mem_map::IO_LO + ofs);
+                let value = self.read_byte(mem_map::IO_LO + ofs);
                 self.regs.write8(Reg8::A, value);
             },
             Op::in8_imm => {
                 let ofs = instr.imm.imm8() as u16;
-                let value = self.bus.read(mem_map::IO_LO + ofs);
+                let value = self.read_byte(mem_map::IO_LO + ofs);
                 self.regs.write8(Reg8::A, value);
             },
             Op::out8_reg => {
                 let value = self.regs.read8(Reg8::A);
                 let ofs = self.regs.read8(Reg8::C) as u16;
-                self.bus.write(mem_map::IO_LO + ofs, value)
+                self.write_byte(mem_map::IO_LO + ofs, value)
             },
             Op::out8_imm => {
                 let value = self.regs.read8(Reg8::A);
                 let ofs = instr.imm.imm8() as u16;
-                self.bus.write(mem_map::IO_LO + ofs, value)
+                self.write_byte(mem_map::IO_LO + ofs, value)
             },
 
             /* ALU instructions */
@@ -238,7 +348,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::add8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let value = self.alu_add_bytes(a, b, false);
                 self.regs.write8(Reg8::A, value);
             },
@@ -270,7 +380,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::adc8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let value = self.alu_add_bytes(a, b, true);
                 self.regs.write8(Reg8::A, value);
             },
@@ -290,7 +400,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::sub8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let value = self.alu_sub_bytes(a, b, false);
                 self.regs.write8(Reg8::A, value);
             },
@@ -310,7 +420,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::sbc8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let value = self.alu_sub_bytes(a, b, true);
                 self.regs.write8(Reg8::A, value);
             },
@@ -330,7 +440,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::and8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let value = self.alu_and_bytes(a, b);
                 self.regs.write8(Reg8::A, value);
             },
@@ -350,7 +460,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::or8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let value = self.alu_or_bytes(a, b);
                 self.regs.write8(Reg8::A, value);
             },
@@ -370,7 +480,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::xor8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let value = self.alu_xor_bytes(a, b);
                 self.regs.write8(Reg8::A, value);
             },
@@ -391,10 +501,10 @@ impl<B> Cpu<B> where B: Bus {
             Op::inc8_ind => {
                 let carry = self.regs.f.contains(CARRY);
                 let addr = self.regs.read16(Reg16::HL);
-                let a = self.bus.read(addr);
+                let a = self.read_byte(addr);
                 let value = self.alu_add_bytes(a, 1, false);
                 self.regs.f.force(CARRY, carry);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
             Op::inc16_reg { src } => {
                 let value = self.regs.read16(src).wrapping_add(1);
@@ -411,10 +521,10 @@ impl<B> Cpu<B> where B: Bus {
             Op::dec8_ind => {
                 let carry = self.regs.f.contains(CARRY);
                 let addr = self.regs.read16(Reg16::HL);
-                let a = self.bus.read(addr);
+                let a = self.read_byte(addr);
                 let value = self.alu_sub_bytes(a, 1, false);
                 self.regs.f.force(CARRY, carry);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
             Op::dec16_reg { src } => {
                 let value = self.regs.read16(src).wrapping_sub(1);
@@ -429,7 +539,7 @@ impl<B> Cpu<B> where B: Bus {
             Op::cp8_ind => {
                 let addr = self.regs.read16(Reg16::HL);
                 let a = self.regs.read8(Reg8::A);
-                let b = self.bus.read(addr);
+                let b = self.read_byte(addr);
                 let _ = self.alu_sub_bytes(a, b, false);
             },
             Op::cp8_imm => {
@@ -445,9 +555,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::swap_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let value = self.bus.read(addr);
+                let value = self.read_byte(addr);
                 self.regs.f = ZERO.test(value == 0);
-                self.bus.write(addr, (value & 0xf) << 4 | (value >> 4));
+                self.write_byte(addr, (value & 0xf) << 4 | (value >> 4));
             },
 
             /* Rotate & shift instructions */
@@ -464,9 +574,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::rl_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let mut value = self.bus.read(addr);
+                let mut value = self.read_byte(addr);
                 value = self.rotate_left(value);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
 
             Op::rlca => {
@@ -482,9 +592,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::rlc_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let mut value = self.bus.read(addr);
+                let mut value = self.read_byte(addr);
                 value = self.rotate_left_carry(value);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             }
 
             Op::rra => {
@@ -500,9 +610,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::rr_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let mut value = self.bus.read(addr);
+                let mut value = self.read_byte(addr);
                 value = self.rotate_right(value);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
 
             Op::rrca => {
@@ -518,9 +628,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::rrc_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let mut value = self.bus.read(addr);
+                let mut value = self.read_byte(addr);
                 value = self.rotate_right_carry(value);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
 
             Op::sla { src } => {
@@ -530,9 +640,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::sla_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let mut value = self.bus.read(addr);
+                let mut value = self.read_byte(addr);
                 value = self.shift_left_arithmetic(value);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
             Op::sra { src } => {
                 let mut value = self.regs.read8(src);
@@ -541,9 +651,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::sra_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let mut value = self.bus.read(addr);
+                let mut value = self.read_byte(addr);
                 value = self.shift_right_arithmetic(value);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
             Op::srl { src } => {
                 let mut value = self.regs.read8(src);
@@ -552,9 +662,9 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::srl_ind => {
                 let addr = self.regs.read16(Reg16::HL);
-                let mut value = self.bus.read(addr);
+                let mut value = self.read_byte(addr);
                 value = self.shift_right_logical(value);
-                self.bus.write(addr, value);
+                self.write_byte(addr, value);
             },
 
             /* Bit operation instructions */
@@ -566,7 +676,7 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::bit_ind { bit } => {
                 let addr = self.regs.read16(Reg16::HL);
-                let value = self.bus.read(addr);
+                let value = self.read_byte(addr);
                 self.regs.f = ZERO.test(get_bit!(value, bit) == 0) |
                               HCARRY |
                               CARRY.test(self.regs.f.contains(CARRY));
@@ -577,8 +687,8 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::set_ind { bit } => {
                 let addr = self.regs.read16(Reg16::HL);
-                let value = self.bus.read(addr);
-                self.bus.write(addr, set_bit!(value, bit));
+                let value = self.read_byte(addr);
+                self.write_byte(addr, set_bit!(value, bit));
             },
             Op::res { src, bit } => {
                 let value = self.regs.read8(src);
@@ -586,8 +696,8 @@ impl<B> Cpu<B> where B: Bus {
             },
             Op::res_ind { bit } => {
                 let addr = self.regs.read16(Reg16::HL);
-                let value = self.bus.read(addr);
-                self.bus.write(addr, reset_bit!(value, bit));
+                let value = self.read_byte(addr);
+                self.write_byte(addr, reset_bit!(value, bit));
             },
 
             /* Jump instructions */
@@ -642,6 +752,8 @@ impl<B> Cpu<B> where B: Bus {
                 self.regs.pc = self.pop();
             },
             Op::ret_cond { ref cond } => {
+                // Checking the condition takes a cycle
+                self.tick();
                 if self.jmp_cond_fulfilled(cond) {
                     self.regs.pc = self.pop();
                     jumped = true;
@@ -654,10 +766,8 @@ impl<B> Cpu<B> where B: Bus {
             },
 
             Op::rst { target } => {
-                self.regs.sp = self.regs.sp.wrapping_sub(2);
-                let addr = self.regs.sp;
-                let value = self.regs.pc;
-                self.write_word(addr, value);
+                let pc = self.regs.pc;
+                self.push(pc);
                 self.regs.pc = target;
             }
 
@@ -705,6 +815,14 @@ impl<B> Cpu<B> where B: Bus {
                 self.regs.f.insert(CARRY);
             },
 
+            Op::inv => {
+                // Point PC back to the illegal opcode to show where it happened
+                self.regs.pc = self.regs.pc.wrapping_sub(1);
+                self.state = CpuState::Locked;
+                self.last_cycles = 1;
+                return;
+            },
+
             _ => panic!("Trying to execute non-implemented instruction {}.\n{}", 
                         instr, self)
         }
@@ -718,7 +836,7 @@ impl<B> Cpu<B> where B: Bus {
 
     fn extract_instr8(&mut self) -> u8 {
         let addr = self.regs.pc;
-        let value = self.bus.read(addr);
+        let value = self.read_byte(addr);
         self.regs.pc = self.regs.pc.wrapping_add(1);
         return value;
     }
@@ -753,18 +871,18 @@ impl<B> Cpu<B> where B: Bus {
 
     fn read_addr(&mut self, addr: Addr, instr: &Instr) -> u8 {
         let addr_value = self.resolve_addr(addr, instr);
-        self.bus.read(addr_value)
+        self.read_byte(addr_value)
     }
 
     fn write_addr(&mut self, addr: Addr, instr: &Instr, value: u8) {
         let addr_value = self.resolve_addr(addr, instr);
-        self.bus.write(addr_value, value)
+        self.write_byte(addr_value, value)
     }
 
     #[inline(always)]
     fn read_word(&mut self, addr: u16) -> u16 {
-        let lo = self.bus.read(addr);
-        let hi = self.bus.read(addr+1);
+        let lo = self.read_byte(addr);
+        let hi = self.read_byte(addr+1);
         ((hi as u16) << 8) + lo as u16
     }
 
@@ -772,18 +890,82 @@ impl<B> Cpu<B> where B: Bus {
     fn write_word(&mut self, addr: u16, value: u16) {
         let lo = (value & 0x00ff) as u8;
         let hi = (value >> 8) as u8;
-        self.bus.write(addr, lo);
-        self.bus.write(addr+1, hi);
+        self.write_byte(addr, lo);
+        self.write_byte(addr+1, hi);
     }
 
-    pub fn total_cycles(&self) -> u64 {
-        self.total_cycles
+    pub fn is_locked(&self) -> bool {
+        self.state == CpuState::Locked
     }
 
     pub fn hardware(&mut self) -> &mut B {
         &mut self.bus
     }
 
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    // Only covers the CPU itself, the bus is saved separately
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.regs.pc);
+        writer.write_u16(self.regs.sp);
+        writer.write_bytes(&[self.regs.a, self.regs.f.bits(),
+                             self.regs.b, self.regs.c,
+                             self.regs.d, self.regs.e,
+                             self.regs.h, self.regs.l]);
+
+        writer.write_u8(match self.state {
+            CpuState::Running => 0,
+            CpuState::Halted => 1,
+            CpuState::Stopped => 2,
+            CpuState::Locked => 3,
+        });
+        writer.write_bool(self.halt_bug);
+        writer.write_bool(self.int_flag);
+        writer.write_u8(match self.int_enable {
+            IntEnable::No => 0,
+            IntEnable::Pending => 1,
+            IntEnable::Enable => 2,
+        });
+
+        writer.write_u8(self.last_cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.regs.pc = try!(reader.read_u16());
+        self.regs.sp = try!(reader.read_u16());
+        let mut regs = [0; 8];
+        try!(reader.read_bytes(&mut regs));
+        self.regs.a = regs[0];
+        self.regs.f = Flags::from_bits_truncate(regs[1]);
+        self.regs.b = regs[2];
+        self.regs.c = regs[3];
+        self.regs.d = regs[4];
+        self.regs.e = regs[5];
+        self.regs.h = regs[6];
+        self.regs.l = regs[7];
+
+        self.state = match try!(reader.read_u8()) {
+            0 => CpuState::Running,
+            1 => CpuState::Halted,
+            2 => CpuState::Stopped,
+            3 => CpuState::Locked,
+            _ => return Err(Error::BadState("invalid CPU state".to_string()))
+        };
+        self.halt_bug = try!(reader.read_bool());
+        self.int_flag = try!(reader.read_bool());
+        self.int_enable = match try!(reader.read_u8()) {
+            0 => IntEnable::No,
+            1 => IntEnable::Pending,
+            2 => IntEnable::Enable,
+            _ => return Err(Error::BadState("invalid interrupt enable state".to_string()))
+        };
+
+        self.last_cycles = try!(reader.read_u8());
+        Ok(())
+    }
+
     fn alu_add_bytes(&mut self, a: u8, b: u8, with_carry: bool) -> u8 {
         let carry = if self.regs.f.contains(CARRY) {with_carry as u8} else {0};
         let bc = b.wrapping_add(carry);
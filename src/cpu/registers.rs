@@ -67,6 +67,39 @@ impl Registers {
         }
     }
 
+    // Register state after the DMG boot ROM has finished
+    pub fn post_boot() -> Registers {
+        Registers {
+            pc: 0x0100,
+            sp: 0xFFFE,
+            a: 0x01,
+            f: ZERO | HCARRY | CARRY,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D
+        }
+    }
+
+    // Register state after the CGB boot ROM has finished. Games check
+    // for A = 0x11 to detect that they run on a CGB.
+    pub fn post_boot_cgb() -> Registers {
+        Registers {
+            pc: 0x0100,
+            sp: 0xFFFE,
+            a: 0x11,
+            f: ZERO,
+            b: 0x00,
+            c: 0x00,
+            d: 0xFF,
+            e: 0x56,
+            h: 0x00,
+            l: 0x0D
+        }
+    }
+
     pub fn read8(&self, src: Reg8) -> u8 {
         match src {
             Reg8::A => self.a,
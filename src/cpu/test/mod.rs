@@ -35,7 +35,13 @@ mod test_swap;
 mod test_bits;
 
 struct TestHardware {
-    memory: Vec<u8>
+    memory: Vec<u8>,
+    irq: bool,
+    // Only VBlank (bit 0) matters, IE is written by the interrupt push
+    ie: u8,
+    // Elapsed cycles and the cycle at which each write happened
+    cycles: u64,
+    writes: Vec<(u64, u16)>,
 }
 
 impl Bus for TestHardware {
@@ -45,19 +51,59 @@ impl Bus for TestHardware {
 
     fn write(&mut self, addr: u16, value: u8) {
         self.memory[addr as usize] = value;
+        if addr == 0xffff {
+            self.ie = value;
+        }
+        self.writes.push((self.cycles, addr));
     }
 
-    fn has_irq(&self) -> bool { false }
-    fn ack_irq(&mut self) -> Option<Interrupt> { None }
-    fn update(&mut self, _: u8) -> Events { Events::empty() }
+    fn has_irq(&self) -> bool { self.irq && self.ie & 0x01 != 0 }
+
+    fn ack_irq(&mut self) -> Option<Interrupt> {
+        if self.has_irq() {
+            self.irq = false;
+            Some(Interrupt::VBlank)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, cycles: u8) -> Events {
+        self.cycles += cycles as u64;
+        Events::empty()
+    }
+
+    fn tick(&mut self) -> Events {
+        self.cycles += 1;
+        Events::empty()
+    }
+
+    fn stall_cycles(&mut self) -> u8 { 0 }
+    fn stop(&mut self) -> bool { false }
+    fn is_stopped(&self) -> bool { false }
 }
 
 fn create_hardware(memory: Vec<u8>) -> TestHardware {
     TestHardware {
-        memory: memory
+        memory: memory,
+        irq: false,
+        ie: 0xff,
+        cycles: 0,
+        writes: Vec::new(),
     }
 }
 
+// A CPU with the code at 0x0000 in 64K of memory and the stack at the top
+fn create_cpu(code: &[u8], ime: bool) -> Cpu<TestHardware> {
+    let mut memory = code.to_vec();
+    memory.resize(0x10000, 0x00);
+
+    let mut cpu = Cpu::new(create_hardware(memory));
+    cpu.regs.sp = 0xfffe;
+    cpu.int_flag = ime;
+    cpu
+}
+
 fn run_test<F: Fn(&mut Cpu<TestHardware>)->()> (code: &[u8], init: F) 
     -> Cpu<TestHardware> {
     let mut cpu = Cpu::new(create_hardware(code.to_vec()));
@@ -103,7 +149,7 @@ fn test_opcodes() {
         (vec![0x0E, 0xff], Op::ld8_imm { dest: C }, Imm8(0xff)),
         (vec![0x0F], Op::rrca, None),
 
-        (vec![0x10], Op::stop, None),
+        (vec![0x10, 0x00], Op::stop, Imm8(0x00)),
         (vec![0x11, 0x99, 0x11], Op::ld16_imm { dest: DE }, Imm16(0x1199)),
         (vec![0x12], Op::st8_ind { dest: Addr::DE, src: A }, None),
         (vec![0x13], Op::inc16_reg { src: DE }, None),
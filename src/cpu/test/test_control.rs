@@ -14,10 +14,10 @@
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 use hardware::Bus;
-use cpu::IntEnable;
+use cpu::{CpuState, IntEnable};
 use cpu::registers::{Flags, ZERO, CARRY};
 use instructions::{Instr, Reg16, Immediate, Op, Condition};
-use super::test_instr;
+use super::{create_cpu, test_instr};
 
 fn conditions() -> [(Condition, Flags, Flags); 4] {
     [(Condition::NZ, Flags::empty(), ZERO),
@@ -305,4 +305,142 @@ fn test_reset() {
         assert_eq!(cpu.bus.read(0x0001), 0x14);
         assert_eq!(cpu.bus.read(0x0002), 0x52);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_halt() {
+    // HALT
+    let mut cpu = create_cpu(&[0x76, 0x3c], false);
+    cpu.step();
+    cpu.step();
+
+    assert_eq!(cpu.state, CpuState::Halted);
+    assert_eq!(cpu.regs.pc, 0x0001);
+}
+
+#[test]
+fn test_halt_wakeup_ime_disabled() {
+    // HALT, INC A
+    let mut cpu = create_cpu(&[0x76, 0x3c], false);
+    cpu.step();
+    cpu.bus.irq = true;
+    cpu.step();
+
+    assert_eq!(cpu.state, CpuState::Running);
+    assert_eq!(cpu.bus.cycles, 2);
+
+    // Execution continues without servicing the interrupt
+    cpu.step();
+    assert_eq!(cpu.regs.pc, 0x0002);
+    assert_eq!(cpu.regs.a, 0x01);
+    assert!(cpu.bus.irq);
+}
+
+#[test]
+fn test_halt_wakeup_ime_enabled() {
+    // HALT, INC A
+    let mut cpu = create_cpu(&[0x76, 0x3c], true);
+    cpu.step();
+    cpu.bus.irq = true;
+    cpu.step();
+
+    assert_eq!(cpu.state, CpuState::Running);
+    assert_eq!(cpu.bus.cycles, 2);
+    assert_eq!(cpu.regs.pc, 0x0001);
+
+    // The VBlank handler runs next
+    cpu.step();
+    assert_eq!(cpu.regs.pc, 0x0041);
+    assert_eq!(cpu.regs.sp, 0xfffc);
+    assert_eq!(cpu.bus.memory[0xfffc], 0x01);
+    assert!(!cpu.bus.irq);
+}
+
+#[test]
+fn test_halt_after_ei() {
+    // EI, HALT
+    let mut cpu = create_cpu(&[0xfb, 0x76, 0x00], false);
+    cpu.bus.irq = true;
+    cpu.step();
+    cpu.step();
+
+    // The interrupt is serviced without halting first
+    assert_eq!(cpu.state, CpuState::Running);
+    assert_eq!(cpu.regs.pc, 0x0001);
+
+    // and returns to the HALT itself
+    cpu.step();
+    assert_eq!(cpu.regs.pc, 0x0041);
+    assert_eq!(cpu.bus.memory[0xfffc], 0x01);
+    assert_eq!(cpu.bus.memory[0xfffd], 0x00);
+}
+
+#[test]
+fn test_halt_bug() {
+    // HALT, LD A 0x14 with a pending interrupt and IME disabled
+    let mut cpu = create_cpu(&[0x76, 0x3e, 0x14], false);
+    cpu.bus.irq = true;
+    cpu.step();
+
+    assert_eq!(cpu.state, CpuState::Running);
+    assert_eq!(cpu.regs.pc, 0x0001);
+
+    // The opcode byte is read twice: LD A 0x3e, then INC D
+    cpu.step();
+    assert_eq!(cpu.regs.a, 0x3e);
+    assert_eq!(cpu.regs.pc, 0x0002);
+
+    cpu.step();
+    assert_eq!(cpu.regs.d, 0x01);
+    assert_eq!(cpu.regs.pc, 0x0003);
+}
+
+#[test]
+fn test_access_timing() {
+    // CALL 0x1234: the return address is pushed in the last two cycles
+    let mut cpu = create_cpu(&[0xcd, 0x34, 0x12], false);
+    cpu.step();
+
+    assert_eq!(cpu.regs.pc, 0x1234);
+    assert_eq!(cpu.bus.cycles, 6);
+    assert_eq!(cpu.bus.writes, vec![(5, 0xfffd), (6, 0xfffc)]);
+
+    // PUSH BC
+    let mut cpu = create_cpu(&[0xc5], false);
+    cpu.step();
+
+    assert_eq!(cpu.bus.cycles, 4);
+    assert_eq!(cpu.bus.writes, vec![(3, 0xfffd), (4, 0xfffc)]);
+}
+
+#[test]
+fn test_interrupt_dispatch() {
+    let mut cpu = create_cpu(&[], true);
+    cpu.regs.pc = 0x0134;
+    cpu.bus.irq = true;
+    cpu.handle_interrupts();
+    cpu.handle_updates();
+
+    assert_eq!(cpu.regs.pc, 0x0040);
+    assert_eq!(cpu.regs.sp, 0xfffc);
+    assert_eq!(cpu.bus.cycles, 5);
+    assert_eq!(cpu.bus.writes, vec![(3, 0xfffd), (4, 0xfffc)]);
+    assert!(!cpu.int_flag);
+}
+
+#[test]
+fn test_interrupt_ie_push() {
+    // The high byte of PC is pushed to IE and disables VBlank
+    let mut cpu = create_cpu(&[], true);
+    cpu.regs.pc = 0x0234;
+    cpu.regs.sp = 0x0000;
+    cpu.bus.irq = true;
+    cpu.handle_interrupts();
+    cpu.handle_updates();
+
+    assert_eq!(cpu.regs.pc, 0x0000);
+    assert_eq!(cpu.regs.sp, 0xfffe);
+    assert_eq!(cpu.bus.memory[0xfffe], 0x34);
+    assert_eq!(cpu.bus.cycles, 5);
+    assert!(cpu.bus.irq);
+}
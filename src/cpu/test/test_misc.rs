@@ -13,7 +13,9 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
-use super::{run_test, test_instr};
+use super::{create_hardware, run_test, test_instr};
+use cpu::{Cpu, CpuState};
+use events;
 use cpu::registers::{ZERO, SUB, HCARRY, CARRY};
 use instructions::{Instr, Reg8, Immediate, Op};
 
@@ -25,7 +27,36 @@ fn test_nop() {
         |_| {}
     );
 
-    assert_eq!(cpu.total_cycles, 1);
+    assert_eq!(cpu.bus.cycles, 1);
+}
+
+#[test]
+fn test_stop() {
+    // STOP 0x00
+    let cpu = run_test(
+        &[0x10, 0x00, 0x00],
+        |_| {}
+    );
+
+    assert_eq!(cpu.regs.pc, 2);
+    assert_eq!(cpu.state, CpuState::Stopped);
+}
+
+#[test]
+fn test_illegal_opcode() {
+    // Illegal opcode 0xD3
+    let mut cpu = Cpu::new(create_hardware(vec![0xd3, 0x3c]));
+
+    let events = cpu.step();
+    assert!(events.contains(events::LOCKED));
+    assert_eq!(cpu.state, CpuState::Locked);
+    assert_eq!(cpu.regs.pc, 0x0000);
+
+    // Nothing is fetched anymore
+    let events = cpu.step();
+    assert!(events.is_empty());
+    assert_eq!(cpu.regs.pc, 0x0000);
+    assert_eq!(cpu.regs.a, 0x00);
 }
 
 // TODO:
@@ -15,9 +15,10 @@
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 use cpu;
 use hardware;
-use gpu::{self, Color, SCREEN_WIDTH};
+use gpu::{self, SCREEN_WIDTH};
 use instructions::Instr;
 use cpu::debug::DebugInfo;
+use error::Error;
 
 use std::io::{self, Write};
 
@@ -32,6 +33,7 @@ fn print_help() {
     println!("[print ]cpu: Print current CPU state");
     println!("read <addr>: Read memory address <addr>");
     println!("auto <instr|cpu>: Automatically print item after instructions");
+    println!("header: Print the cartridge header");
 }
 
 fn print_instr(addr: u16, instr: &Instr) {
@@ -41,22 +43,21 @@ fn print_instr(addr: u16, instr: &Instr) {
 pub fn print_framebuffer(framebuffer: &gpu::Framebuffer) {
     for i in 0..144 {
         for j in 0..160 {
-            let ch = match framebuffer[i*SCREEN_WIDTH+j] {
-                Color::Black => '■',
-                Color::DarkGray => '▩',
-                Color::LightGray => '▥',
-                Color::White => ' ',
-            };
-            print!("{}", ch);
+            print!("{}", gpu::shade_char(framebuffer[i*SCREEN_WIDTH+j]));
         }
         print!("\n");
     }
     print!("\n");
 }
 
-pub fn start(bios: Box<[u8]>, rom: Box<[u8]>) {
-    let hardware = hardware::Hardware::new(bios, rom);
+pub fn start(bios: Option<Box<[u8]>>, rom: Box<[u8]>) -> Result<(), Error> {
+    let skip_bios = bios.is_none();
+    let hardware = try!(hardware::Hardware::new(bios, rom));
+    let cgb = hardware.is_cgb();
     let mut cpu = cpu::Cpu::new(hardware);
+    if skip_bios {
+        cpu.skip_bios(cgb);
+    }
     let mut db = DebugInfo::new();
 
     let mut last_input = String::new();
@@ -91,6 +92,9 @@ pub fn start(bios: Box<[u8]>, rom: Box<[u8]>) {
             } else if input == "s" || input.starts_with("step") {
                 cur_pc = cpu.get_pc();
                 cpu.single_step(&mut db);
+                if cpu.is_locked() {
+                    println!("CPU locked up at {:#06x}", cpu.get_pc());
+                }
                 print_items = true;
             } else if input == "c" || input.starts_with("continue") {
                 if hit_breakpoint {
@@ -101,7 +105,9 @@ pub fn start(bios: Box<[u8]>, rom: Box<[u8]>) {
 
                 println!("Continuing.");
                 cpu.continue_exec(&mut db);
-                if db.contains_breakpoint(cpu.get_pc()) {
+                if cpu.is_locked() {
+                    println!("CPU locked up at {:#06x}", cpu.get_pc());
+                } else if db.contains_breakpoint(cpu.get_pc()) {
                     println!("Hit breakpoint at {:#06x}", cpu.get_pc());
                     hit_breakpoint = true;
                 } else {
@@ -170,6 +176,8 @@ pub fn start(bios: Box<[u8]>, rom: Box<[u8]>) {
                         _ => println!("Unsupported auto item {}", item)
                     }
                 }
+            } else if input.starts_with("header") {
+                println!("{}", cpu.hardware().cartridge().header());
             } else if input.starts_with("screen") {
                 let framebuffer = cpu.hardware().framebuffer();
                 print_framebuffer(framebuffer);
@@ -193,4 +201,6 @@ pub fn start(bios: Box<[u8]>, rom: Box<[u8]>) {
             break;
         }
     }
+
+    Ok(())
 }
\ No newline at end of file
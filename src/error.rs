@@ -0,0 +1,83 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(String, io::Error),
+    MissingBios(String),
+    InvalidBios(usize),
+    TruncatedRom(usize),
+    BadHeader(String),
+    UnsupportedMapper(u8),
+    BadState(String),
+    StateRomMismatch,
+    BadInputScript(usize, String),
+    Link(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref path, ref why) => write!(f, "Can't access file '{}': {}", path, why),
+            Error::MissingBios(ref path) => write!(f, "BIOS file '{}' not found", path),
+            Error::InvalidBios(len) => {
+                write!(f, "Invalid BIOS: expected 256 bytes, got {}", len)
+            },
+            Error::TruncatedRom(len) => {
+                write!(f, "ROM is truncated: {} bytes are too short for a cartridge header", len)
+            },
+            Error::BadHeader(ref why) => write!(f, "Bad cartridge header: {}", why),
+            Error::UnsupportedMapper(value) => {
+                write!(f, "Unsupported cartridge type {:#04x}", value)
+            },
+            Error::BadState(ref why) => write!(f, "Invalid save state: {}", why),
+            Error::StateRomMismatch => {
+                write!(f, "Save state was made with a different ROM")
+            },
+            Error::BadInputScript(line, ref why) => {
+                write!(f, "Input script line {}: {}", line, why)
+            },
+            Error::Link(ref why) => write!(f, "Link cable: {}", why),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_, ref why) => why.description(),
+            Error::MissingBios(_) => "BIOS file not found",
+            Error::InvalidBios(_) => "invalid BIOS",
+            Error::TruncatedRom(_) => "truncated ROM",
+            Error::BadHeader(_) => "bad cartridge header",
+            Error::UnsupportedMapper(_) => "unsupported cartridge type",
+            Error::BadState(_) => "invalid save state",
+            Error::StateRomMismatch => "save state made with a different ROM",
+            Error::BadInputScript(..) => "invalid input script",
+            Error::Link(_) => "link cable error",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(_, ref why) => Some(why),
+            _ => None
+        }
+    }
+}
@@ -15,6 +15,12 @@
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 bitflags! {
     pub flags Events: u8 {
-        const RENDER = 1
+        const RENDER = 1 << 0,
+        const RUMBLE = 1 << 1,
+        // The CPU hit an illegal opcode and won't execute anything anymore
+        const LOCKED = 1 << 2,
+        // LD B,B was executed, which test ROMs use as a software breakpoint
+        #[cfg(all(test, feature = "conformance"))]
+        const DEBUG_BREAK = 1 << 3
     }
 }
\ No newline at end of file
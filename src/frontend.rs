@@ -16,40 +16,90 @@
 use sdl2;
 use sdl2::pixels::{PixelFormatEnum, Color};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{self, Keycode};
 use sdl2::render::Texture;
+use std::cmp;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use gameboy::Gameboy;
+use audio::Audio;
+use error::Error;
+use gameboy::{Gameboy, CYCLES_PER_FRAME};
+use rewind::Rewind;
 use events;
 use joypad;
-use gpu::{Framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT};
+use gpu::{self, Framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT};
 
 const DISPLAY_WIDTH: usize = 4 * SCREEN_WIDTH;
 const DISPLAY_HEIGHT: usize = 4 * SCREEN_HEIGHT;
 
+// How far back rewinding can go
+const REWIND_SECONDS: u32 = 60;
+
 pub struct Frontend {
     context: sdl2::Sdl,
-    video: sdl2::VideoSubsystem
+    video: sdl2::VideoSubsystem,
+    rom_path: PathBuf,
+
+    rewind_interval: u32,
+    rewind_speed: u32,
 }
 
 impl Frontend {
-    pub fn new() -> Frontend {
+    pub fn new(rom_path: &Path) -> Frontend {
         let context = sdl2::init().unwrap();
         let video = context.video().unwrap();
         Frontend {
             context: context,
-            video: video
+            video: video,
+            rom_path: rom_path.to_path_buf(),
+
+            rewind_interval: 2,
+            rewind_speed: 1,
         }
     }
 
+    // Snapshots are taken every `interval` frames, rewinding plays them back
+    // `speed` times as fast as normal
+    pub fn set_rewind_options(&mut self, interval: u32, speed: u32) {
+        self.rewind_interval = interval;
+        self.rewind_speed = speed;
+    }
+
+    // Save states are stored next to the ROM as .ss1 to .ss10
+    fn state_path(&self, slot: u8) -> PathBuf {
+        self.rom_path.with_extension(format!("ss{}", slot))
+    }
+
+    fn save_state(&self, gameboy: &mut Gameboy, slot: u8) {
+        let path = self.state_path(slot);
+        let data = gameboy.save_state();
+
+        let res = File::create(&path).and_then(|mut file| file.write_all(&data));
+        match res {
+            Ok(_) => println!("Saved state {}", slot),
+            Err(why) => println!("Could not write save state '{}': {}", path.display(), why)
+        }
+    }
+
+    fn load_state(&self, gameboy: &mut Gameboy, slot: u8) -> Result<(), Error> {
+        let path = self.state_path(slot);
+        let mut data = Vec::new();
+        try!(File::open(&path)
+             .and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|why| Error::Io(path.display().to_string(), why)));
+        gameboy.load_state(&data)
+    }
+
     fn update_texture(&self, texture: &mut Texture, framebuffer: &Framebuffer) {
         texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
             for y in 0..SCREEN_HEIGHT {
                 for x in 0..SCREEN_WIDTH {
                     let ofs = y*pitch + x*3;
-                    let (r, g, b) = framebuffer[y*SCREEN_WIDTH + x].to_rgb();
+                    let (r, g, b) = gpu::to_rgb(framebuffer[y*SCREEN_WIDTH + x]);
                     buffer[ofs+0] = r;
                     buffer[ofs+1] = g;
                     buffer[ofs+2] = b;
@@ -78,7 +128,23 @@ impl Frontend {
 
         let mut event_pump = self.context.event_pump().unwrap();
 
+        // Without an audio device, fall back to pacing by the system clock
+        let mut audio = match Audio::new(&self.context) {
+            Ok(audio) => Some(audio),
+            Err(why) => {
+                println!("Could not open audio device: {}", why);
+                None
+            }
+        };
+        let mut samples = Vec::new();
+
+        let capacity = REWIND_SECONDS * 60 / cmp::max(self.rewind_interval, 1);
+        let mut rewind = Rewind::new(self.rewind_interval, capacity as usize);
+        let mut rewinding = false;
+        let mut rewind_progress = 0;
+
         let turbo = false;
+        let mut frames: u64 = 0;
         let frame_duration = Duration::from_secs(1) / 60;
         let mut emu_cycles: u64 = 0;
         let mut last_time = Instant::now();
@@ -96,6 +162,52 @@ impl Frontend {
                         break 'main
                     },
 
+                    Event::KeyDown { keycode: Some(Keycode::Minus), .. } => {
+                        if let Some(ref mut audio) = audio {
+                            audio.decrease_volume();
+                            println!("Volume: {}%", audio.volume());
+                        }
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Equals), .. } => {
+                        if let Some(ref mut audio) = audio {
+                            audio.increase_volume();
+                            println!("Volume: {}%", audio.volume());
+                        }
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::M), .. } => {
+                        if let Some(ref mut audio) = audio {
+                            audio.toggle_mute();
+                            println!("Audio {}", if audio.is_muted() { "muted" }
+                                                 else { "unmuted" });
+                        }
+                    },
+
+                    Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                        rewinding = true;
+                    },
+                    Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => {
+                        rewinding = false;
+                        rewind_progress = 0;
+                    },
+
+                    // F1-F10 load a save state, with Shift they save one
+                    Event::KeyDown { keycode: Some(keycode), keymod, .. }
+                        if Frontend::state_slot(keycode).is_some() => {
+                        let slot = Frontend::state_slot(keycode).unwrap();
+                        if keymod.intersects(keyboard::LSHIFTMOD | keyboard::RSHIFTMOD) {
+                            self.save_state(gameboy, slot);
+                        } else {
+                            match self.load_state(gameboy, slot) {
+                                Ok(_) => {
+                                    println!("Loaded state {}", slot);
+                                    emu_cycles = gameboy.total_cycles();
+                                    self.update_texture(&mut texture, gameboy.framebuffer());
+                                },
+                                Err(why) => println!("Could not load state: {}", why)
+                            }
+                        }
+                    },
+
                     Event::KeyDown { keycode: Some(keycode), .. } => {
                         if let Some(key) = Frontend::map_keycode(keycode) {
                             gameboy.press_key(key);
@@ -110,39 +222,124 @@ impl Frontend {
                 }
             }
 
-            let target_cycles = emu_cycles + if turbo {
-                // Simulate as many cycles as needed for one GB frame update to occur
-                ((4194304 / 4) / 60) as u64
+            if rewinding {
+                // Step back through the snapshots, `rewind_speed` times as
+                // fast as they were recorded
+                rewind_progress += self.rewind_speed;
+                while rewind_progress >= rewind.interval() {
+                    rewind_progress -= rewind.interval();
+                    match rewind.step_back(gameboy) {
+                        Ok(true) => {
+                            emu_cycles = gameboy.total_cycles();
+                            self.update_texture(&mut texture, gameboy.framebuffer());
+                        },
+                        Ok(false) => {},
+                        Err(why) => println!("Could not rewind: {}", why)
+                    }
+                }
+
+                // Keep feeding the audio device, it paces the main loop
+                samples.clear();
+                if let Some(ref mut audio) = audio {
+                    samples.resize(2 * audio.sample_rate() as usize / 60, 0);
+                    audio.queue(&samples);
+                }
             } else {
-                // Simulate as many cycles as needed for approx. 1M instructions 
-                // to be executed in a second. This is adapted depending on how
-                // long it takes to execute the instructions plus rendering a frame.
-                (delta * (4194304 / 4)).as_secs()
-            };
+                if let Some(ref mut audio) = audio {
+                    gameboy.set_audio_sample_rate(audio.adjusted_rate());
+                }
 
-            loop {
-                let (cycles, events) = gameboy.simulate(target_cycles);
+                let target_cycles = emu_cycles + if turbo || audio.is_some() {
+                    // Simulate as many cycles as needed for one GB frame update to occur
+                    CYCLES_PER_FRAME
+                } else {
+                    // Simulate as many cycles as needed for approx. 1M instructions 
+                    // to be executed in a second. This is adapted depending on how
+                    // long it takes to execute the instructions plus rendering a frame.
+                    (delta * (4194304 / 4)).as_secs()
+                };
 
-                if events.contains(events::RENDER) {
-                    self.update_texture(&mut texture, gameboy.framebuffer());
+                loop {
+                    let (cycles, events) = gameboy.simulate(target_cycles);
+
+                    if events.contains(events::RENDER) {
+                        self.update_texture(&mut texture, gameboy.framebuffer());
+                    }
+
+                    if events.contains(events::RUMBLE) {
+                        let title = if gameboy.rumble() { "GBEmu (rumble)" }
+                                    else { "GBEmu" };
+                        renderer.window_mut().unwrap().set_title(title).unwrap();
+                    }
+
+                    if events.contains(events::LOCKED) {
+                        println!("CPU locked up at {:#06x}", gameboy.pc());
+                        renderer.window_mut().unwrap().set_title("GBEmu (locked up)").unwrap();
+                    }
+
+                    if cycles >= target_cycles {
+                        emu_cycles = cycles;
+                        break;
+                    }
                 }
 
-                if cycles >= target_cycles {
-                    emu_cycles = cycles;
-                    break;
+                samples.clear();
+                gameboy.drain_audio(&mut samples);
+                if let Some(ref mut audio) = audio {
+                    audio.queue(&samples);
                 }
+
+                rewind.record(gameboy);
             }
 
             renderer.copy(&texture, None, None);
             renderer.present();
 
-            if !turbo {
+            // Write back battery RAM about once per second if the game changed it
+            frames += 1;
+            if frames % 60 == 0 {
+                if let Err(why) = gameboy.autosave() {
+                    println!("Could not write save file: {}", why);
+                }
+            }
+
+            if turbo {
+                continue;
+            }
+
+            if let Some(ref mut audio) = audio {
+                // The audio device consumes samples at a fixed rate, so wait
+                // until it has room for the next frame
+                while !audio.has_space() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            } else {
                 let now = Instant::now();
                 if now < target_time {
                     thread::sleep(target_time - now);
                 }
             }
         }
+
+        if let Err(why) = gameboy.save() {
+            println!("Could not write save file: {}", why);
+        }
+    }
+
+    fn state_slot(keycode: Keycode) -> Option<u8> {
+        match keycode {
+            Keycode::F1  => Some(1),
+            Keycode::F2  => Some(2),
+            Keycode::F3  => Some(3),
+            Keycode::F4  => Some(4),
+            Keycode::F5  => Some(5),
+            Keycode::F6  => Some(6),
+            Keycode::F7  => Some(7),
+            Keycode::F8  => Some(8),
+            Keycode::F9  => Some(9),
+            Keycode::F10 => Some(10),
+            _            => None
+        }
     }
 
     fn map_keycode(keycode: Keycode) -> Option<joypad::Key> {
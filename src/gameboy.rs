@@ -14,40 +14,89 @@
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
 
 use cpu;
+use error::Error;
 use hardware;
+use serial::SerialDevice;
 use joypad;
 use gpu;
 use events;
+use state::{StateReader, StateWriter};
+
+// One frame of the LCD: 154 lines of 114 cycles each
+pub const CYCLES_PER_FRAME: u64 = 17556;
 
 pub struct Gameboy {
-    cpu: cpu::Cpu<hardware::Hardware>
+    cpu: cpu::Cpu<hardware::Hardware>,
+    save_path: Option<PathBuf>,
 }
 
 impl Gameboy {
-    pub fn new(bios: Box<[u8]>, rom: Box<[u8]>) -> Gameboy {
-        let hardware = hardware::Hardware::new(bios, rom);
+    pub fn new(bios: Option<Box<[u8]>>, rom: Box<[u8]>) -> Result<Gameboy, Error> {
+        let skip_bios = bios.is_none();
+        let hardware = try!(hardware::Hardware::new(bios, rom));
 
-        Gameboy {
-            cpu: cpu::Cpu::new(hardware)
+        let cgb = hardware.is_cgb();
+        let mut cpu = cpu::Cpu::new(hardware);
+        if skip_bios {
+            cpu.skip_bios(cgb);
         }
+
+        Ok(Gameboy {
+            cpu: cpu,
+            save_path: None,
+        })
     }
 
     pub fn simulate(&mut self, target_cycles: u64) -> (u64, events::Events) {
-        while self.cpu.total_cycles() < target_cycles {
+        while self.total_cycles() < target_cycles {
             let events = self.cpu.step();
             if !events.is_empty() {
-                return (self.cpu.total_cycles(), events)
+                return (self.total_cycles(), events)
             }
         }
-        (self.cpu.total_cycles(), events::Events::empty())
+        (self.total_cycles(), events::Events::empty())
+    }
+
+    // Elapsed time in normal speed cycles, which CGB double speed doesn't change
+    pub fn total_cycles(&self) -> u64 {
+        self.cpu.bus().clock()
+    }
+
+    // After a lock up, this is the address of the illegal opcode
+    pub fn pc(&self) -> u16 {
+        self.cpu.get_pc()
+    }
+
+    // Memory and register access for the test ROM harness
+    #[cfg(all(test, feature = "conformance"))]
+    pub fn read_mem(&self, addr: u16) -> u8 {
+        self.cpu.read_mem(addr)
+    }
+
+    #[cfg(all(test, feature = "conformance"))]
+    pub fn read_reg8(&self, reg: ::instructions::Reg8) -> u8 {
+        self.cpu.read_reg8(reg)
     }
 
     pub fn framebuffer(&mut self) -> &gpu::Framebuffer {
         self.cpu.hardware().framebuffer()
     }
 
+    // Sample rate of the generated audio in Hz
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.hardware().set_audio_sample_rate(sample_rate);
+    }
+
+    // Appends the stereo samples generated so far (interleaved, left first)
+    pub fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        self.cpu.hardware().drain_audio(out);
+    }
+
     pub fn press_key(&mut self, key: joypad::Key) {
         self.cpu.hardware().press_key(key);
     }
@@ -55,6 +104,126 @@ impl Gameboy {
     pub fn release_key(&mut self, key: joypad::Key) {
         self.cpu.hardware().release_key(key);
     }
+
+    pub fn set_rtc_host_sync(&mut self, enabled: bool) {
+        self.cpu.hardware().set_rtc_host_sync(enabled);
+    }
+
+    // Without a device, bytes sent over the serial port are captured
+    pub fn set_serial_device(&mut self, device: Option<Box<SerialDevice>>) {
+        self.cpu.hardware().set_serial_device(device);
+    }
+
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.cpu.hardware().take_serial_output()
+    }
+
+    pub fn rumble(&mut self) -> bool {
+        self.cpu.hardware().rumble()
+    }
+
+    pub fn set_save_path(&mut self, path: Option<PathBuf>) {
+        self.save_path = path;
+    }
+
+    fn battery_save_path(&mut self) -> Option<PathBuf> {
+        if self.cpu.hardware().cartridge().has_battery() {
+            self.save_path.clone()
+        } else {
+            None
+        }
+    }
+
+    pub fn load_save(&mut self) -> io::Result<()> {
+        let path = match self.battery_save_path() {
+            Some(path) => path,
+            None => return Ok(())
+        };
+
+        let mut file = match File::open(&path) {
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(()),
+            res => try!(res)
+        };
+
+        let mut buf = Vec::new();
+        try!(file.read_to_end(&mut buf));
+        self.cpu.hardware().cartridge().load_save_data(&buf);
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match self.battery_save_path() {
+            Some(path) => path,
+            None => return Ok(())
+        };
+
+        let data = self.cpu.hardware().cartridge().save_data();
+
+        // Write to a temporary file first to not lose the old save on errors
+        let tmp_path = path.with_extension("sav.tmp");
+        {
+            let mut file = try!(File::create(&tmp_path));
+            try!(file.write_all(&data));
+        }
+        fs::rename(&tmp_path, &path)
+    }
+
+    pub fn autosave(&mut self) -> io::Result<()> {
+        if self.cpu.hardware().cartridge().take_ram_modified() {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn rom_checksums(&mut self) -> (u8, u16) {
+        let header = self.cpu.hardware().cartridge().header();
+        (header.header_checksum, header.global_checksum)
+    }
+
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let (header_checksum, global_checksum) = self.rom_checksums();
+
+        let mut writer = StateWriter::new();
+        writer.write_header(header_checksum, global_checksum);
+        self.cpu.save_state(&mut writer);
+        self.cpu.hardware().save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let (header_checksum, global_checksum) = self.rom_checksums();
+
+        let mut reader = StateReader::new(data);
+        try!(reader.read_header(header_checksum, global_checksum));
+
+        // Don't leave the machine half-loaded if the state turns out to be broken
+        let backup = self.save_state();
+        let res = self.load_components(&mut reader);
+        if res.is_err() {
+            let mut reader = StateReader::new(&backup);
+            reader.read_header(header_checksum, global_checksum)
+                .and_then(|_| self.load_components(&mut reader))
+                .expect("Restoring the previous state failed");
+        }
+        res
+    }
+
+    // For snapshots this instance saved itself, like the rewind buffer's.
+    // They are loaded many times per second, so no backup is taken.
+    pub fn load_snapshot(&mut self, data: &[u8]) -> Result<(), Error> {
+        let (header_checksum, global_checksum) = self.rom_checksums();
+
+        let mut reader = StateReader::new(data);
+        try!(reader.read_header(header_checksum, global_checksum));
+        self.load_components(&mut reader)
+    }
+
+    fn load_components(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        try!(self.cpu.load_state(reader));
+        try!(self.cpu.hardware().load_state(reader));
+        reader.finish()
+    }
 }
 
 impl fmt::Display for Gameboy {
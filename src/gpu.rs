@@ -15,7 +15,9 @@
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 use std::cmp::{self, Ordering};
 use super::int_controller::{Interrupt, IntController};
+use error::Error;
 use events;
+use state::{StateReader, StateWriter};
 
 pub const SCREEN_WIDTH:     usize = 160;
 pub const SCREEN_HEIGHT:    usize = 144;
@@ -29,8 +31,29 @@ const TILE_WIDTH:           usize = 8;
 const TILE_HEIGHT:          usize = 8;
 const TILE_DATA0_OFS:       usize = 256;
 const OAM_ENTRY_SIZE:       usize = 4;
+const COLOR_PALETTE_SIZE:   usize = 64;
 
-pub type Framebuffer = [Color; SCREEN_WIDTH * SCREEN_HEIGHT];
+// Colors are stored as 15-bit BGR555, the native CGB color format
+pub type Framebuffer = [u16; SCREEN_WIDTH * SCREEN_HEIGHT];
+
+pub fn to_rgb(color: u16) -> (u8, u8, u8) {
+    let expand = |value: u16| {
+        let value = (value & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+    (expand(color), expand(color >> 5), expand(color >> 10))
+}
+
+// Approximates a color with a shade character for terminal output
+pub fn shade_char(color: u16) -> char {
+    let (r, g, b) = to_rgb(color);
+    match (r as u32 + g as u32 + b as u32) / 3 {
+        0x00 ... 0x3F => '■',
+        0x40 ... 0x7F => '▩',
+        0x80 ... 0xDF => '▥',
+        _             => ' ',
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 struct Tile {
@@ -62,12 +85,12 @@ pub enum Color {
 }
 
 impl Color {
-    pub fn to_rgb(&self) -> (u8, u8, u8) {
+    fn to_rgb15(&self) -> u16 {
         match *self {
-            Color::White       => (0xff, 0xff, 0xff),
-            Color::LightGray   => (0xc0, 0xc0, 0xc0),
-            Color::DarkGray    => (0x60, 0x60, 0x60),
-            Color::Black       => (0x00, 0x00, 0x00)
+            Color::White       => 0x7FFF,
+            Color::LightGray   => 0x6318,
+            Color::DarkGray    => 0x318C,
+            Color::Black       => 0x0000
         }
     }
 }
@@ -98,12 +121,71 @@ impl Palette {
     }
 }
 
+// CGB palette memory, accessed through an index and a data register
+#[derive(Copy, Clone)]
+struct ColorPalette {
+    pub data: [u8; COLOR_PALETTE_SIZE],
+    pub index: u8,
+    pub auto_increment: bool,
+}
+
+impl ColorPalette {
+    fn new() -> ColorPalette {
+        ColorPalette {
+            data: [0xFF; COLOR_PALETTE_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    fn read_index(&self) -> u8 {
+        let inc = if self.auto_increment { 0x80 } else { 0x00 };
+        inc | 0x40 | self.index
+    }
+
+    fn write_index(&mut self, value: u8) {
+        self.index = value & 0x3F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    fn get_color(&self, palette: u8, color_id: u8) -> u16 {
+        let idx = (palette as usize & 0b111) * 8 + (color_id as usize & 0b11) * 2;
+        let lo = self.data[idx] as u16;
+        let hi = self.data[idx+1] as u16;
+        ((hi << 8) | lo) & 0x7FFF
+    }
+}
+
 bitflags! {
     flags SpriteFlags: u8 {
-        const BG_PRIO   = 1 << 7,
-        const Y_FLIP    = 1 << 6,
-        const X_FLIP    = 1 << 5,
-        const PALETTE1  = 1 << 4        
+        const BG_PRIO       = 1 << 7,
+        const Y_FLIP        = 1 << 6,
+        const X_FLIP        = 1 << 5,
+        const PALETTE1      = 1 << 4,
+        const CGB_BANK      = 1 << 3,
+        const CGB_PALETTE   = 0b111,
+    }
+}
+
+// Background map attributes, stored in VRAM bank 1 (CGB only)
+bitflags! {
+    flags TileAttrs: u8 {
+        const TILE_PRIO     = 1 << 7,
+        const TILE_Y_FLIP   = 1 << 6,
+        const TILE_X_FLIP   = 1 << 5,
+        const TILE_BANK     = 1 << 3,
+        const TILE_PALETTE  = 0b111,
     }
 }
 
@@ -135,7 +217,6 @@ enum GpuMode {
 }
 
 impl GpuMode {
-    #[allow(dead_code)]
     fn from_bits(value: u8) -> GpuMode {
         match value & 0b11 {
             0b00 => GpuMode::HBlank,
@@ -190,12 +271,19 @@ pub struct Gpu {
     obj_palette0: Palette,
     obj_palette1: Palette,
 
-    tiles: [Tile; NUM_TILES],
+    cgb: bool,
+    vram_bank: usize,
+    bg_color_palette: ColorPalette,
+    obj_color_palette: ColorPalette,
+
+    tiles: [[Tile; NUM_TILES]; 2],
     tile_map: [[u8; TILE_MAP_SIZE]; 2],
+    tile_attrs: [[u8; TILE_MAP_SIZE]; 2],
 
     oam: [Sprite; NUM_SPRITES],
 
     framebuffer: Framebuffer,
+    hblank_started: bool,
 }
 
 impl Gpu {
@@ -218,15 +306,26 @@ impl Gpu {
             obj_palette0: Palette::new(),
             obj_palette1: Palette::new(),
 
-            tiles: [Tile::new(); NUM_TILES],
+            cgb: false,
+            vram_bank: 0,
+            bg_color_palette: ColorPalette::new(),
+            obj_color_palette: ColorPalette::new(),
+
+            tiles: [[Tile::new(); NUM_TILES]; 2],
             tile_map: [[0; TILE_MAP_SIZE]; 2],
+            tile_attrs: [[0; TILE_MAP_SIZE]; 2],
 
             oam: [Sprite::new(); NUM_SPRITES],
 
-            framebuffer: [Color::White; SCREEN_WIDTH * SCREEN_HEIGHT],
+            framebuffer: [0x7FFF; SCREEN_WIDTH * SCREEN_HEIGHT],
+            hblank_started: false,
         }
     }
 
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
     pub fn step(&mut self, cycles: u8, int_controller: &mut IntController) 
         -> events::Events {
         use self::GpuMode::*;
@@ -297,45 +396,92 @@ impl Gpu {
             VBlank => int_controller.set_int_pending(Interrupt::VBlank),
             _ => {}
         }
+        if mode == HBlank {
+            self.hblank_started = true;
+        }
         self.mode = mode;
     }
 
-    fn get_tile(&self, x: usize, y: usize, use_map1: bool) -> Tile {
+    pub fn in_hblank(&self) -> bool {
+        self.mode == GpuMode::HBlank
+    }
+
+    // Whether HBlank was entered during the last step
+    pub fn take_hblank_start(&mut self) -> bool {
+        let started = self.hblank_started;
+        self.hblank_started = false;
+        started
+    }
+
+    fn get_tile(&self, x: usize, y: usize, use_map1: bool) -> (Tile, TileAttrs) {
         let idx = (y / TILE_HEIGHT) * TILES_IN_SCREEN + x / TILE_WIDTH;
-        let tile_idx = if use_map1 {
-            self.tile_map[1][idx] as usize
+        let map = if use_map1 { 1 } else { 0 };
+        let tile_idx = self.tile_map[map][idx] as usize;
+        let attrs = TileAttrs::from_bits_truncate(self.tile_attrs[map][idx]);
+        let bank = if attrs.contains(TILE_BANK) { 1 } else { 0 };
+
+        let tile = if self.lcdc_reg.contains(TILE_DATA) {
+            self.tiles[bank][tile_idx]
         } else {
-            self.tile_map[0][idx] as usize
+            let ofs = (tile_idx as i8) as i32; // Here, the offset is signed
+            self.tiles[bank][(TILE_DATA0_OFS as i32 + ofs) as usize]
         };
+        (tile, attrs)
+    }
 
-        if self.lcdc_reg.contains(TILE_DATA) {
-            self.tiles[tile_idx]
+    // Returns the color code and attributes of a background or window pixel
+    fn get_bg_pixel(&self, x: usize, y: usize, use_map1: bool) -> (u8, TileAttrs) {
+        let (tile, attrs) = self.get_tile(x, y, use_map1);
+        let x_ofs = if attrs.contains(TILE_X_FLIP) {
+            TILE_WIDTH - 1 - x % TILE_WIDTH
+        } else { x % TILE_WIDTH };
+        let y_ofs = if attrs.contains(TILE_Y_FLIP) {
+            TILE_HEIGHT - 1 - y % TILE_HEIGHT
+        } else { y % TILE_HEIGHT };
+        (tile.get_color_code(x_ofs, y_ofs), attrs)
+    }
+
+    fn get_bg_color(&self, color_code: u8, attrs: TileAttrs) -> u16 {
+        if self.cgb {
+            let palette = (attrs & TILE_PALETTE).bits;
+            self.bg_color_palette.get_color(palette, color_code)
         } else {
-            let ofs = (tile_idx as i8) as i32; // Here, the offset is signed
-            self.tiles[(TILE_DATA0_OFS as i32 + ofs) as usize]
+            self.bg_palette.get_color(color_code).to_rgb15()
+        }
+    }
+
+    fn get_sprite_color(&self, sprite: &Sprite, color_code: u8) -> u16 {
+        if self.cgb {
+            let palette = (sprite.flags & CGB_PALETTE).bits;
+            self.obj_color_palette.get_color(palette, color_code)
+        } else if sprite.flags.contains(PALETTE1) {
+            self.obj_palette1.get_color(color_code).to_rgb15()
+        } else {
+            self.obj_palette0.get_color(color_code).to_rgb15()
         }
     }
 
     fn render_line(&mut self) {
-        let mut bg_prio = [false; SCREEN_WIDTH];
+        // Color code and attributes of the background below each pixel
+        let mut bg_codes = [0u8; SCREEN_WIDTH];
+        let mut bg_attrs = [TileAttrs::empty(); SCREEN_WIDTH];
 
-        if self.lcdc_reg.contains(SHOW_BG) {
+        // On the CGB, LCDC bit 0 doesn't hide the background, but
+        // takes away its priority over sprites
+        let bg_enabled = self.lcdc_reg.contains(SHOW_BG);
+        let line_ofs = self.line as usize * SCREEN_WIDTH;
+
+        if bg_enabled || self.cgb {
             let y = self.line;
             for x in 0..SCREEN_WIDTH {
                 let bg_x = (self.scroll_x as usize + x as usize) % BG_WIDTH;
                 let bg_y = (self.scroll_y as usize + y as usize) % BG_HEIGHT;
-                let y_ofs = bg_y % TILE_HEIGHT;
 
                 let use_map1 = self.lcdc_reg.contains(BG_TILE_MAP);
-                let tile = self.get_tile(bg_x, bg_y, use_map1);
-                let palette = self.bg_palette;
-
-                let x_ofs = x % TILE_WIDTH;
-                let color_code = tile.get_color_code(x_ofs, y_ofs);
-                let color = palette.get_color(color_code);
-                let idx = self.line as usize * SCREEN_WIDTH + x;
-                bg_prio[x] = color != Color::White;
-                self.framebuffer[idx] = color;
+                let (color_code, attrs) = self.get_bg_pixel(bg_x, bg_y, use_map1);
+                bg_codes[x] = color_code;
+                bg_attrs[x] = attrs;
+                self.framebuffer[line_ofs + x] = self.get_bg_color(color_code, attrs);
             }
         }
 
@@ -345,37 +491,34 @@ impl Gpu {
             for x in start_x..SCREEN_WIDTH {
                 let wnd_x = (self.scroll_x as usize + x as usize) % BG_WIDTH;
                 let wnd_y = (self.scroll_y as usize + y as usize) % BG_HEIGHT;
-                let y_ofs = wnd_y % TILE_HEIGHT;
-                
-                let use_map1 = self.lcdc_reg.contains(WINDOW_TILE_MAP);
-                let tile = self.get_tile(wnd_x, wnd_y, use_map1);
-                let palette = self.bg_palette;
 
-                let x_ofs = x % TILE_WIDTH;
-                let color_code = tile.get_color_code(x_ofs, y_ofs);
-                let color = palette.get_color(color_code);
-                let idx = self.line as usize * SCREEN_WIDTH + x;
-                bg_prio[x] = color != Color::White;
-                self.framebuffer[idx] = color;
+                let use_map1 = self.lcdc_reg.contains(WINDOW_TILE_MAP);
+                let (color_code, attrs) = self.get_bg_pixel(wnd_x, wnd_y, use_map1);
+                bg_codes[x] = color_code;
+                bg_attrs[x] = attrs;
+                self.framebuffer[line_ofs + x] = self.get_bg_color(color_code, attrs);
             }
         }
 
         if self.lcdc_reg.contains(SHOW_SPRITES) {
-            let framebuffer = &mut self.framebuffer;
             let sprite_height = if self.lcdc_reg.contains(WIDE_SPRITES) { 16 }
                                 else { 8 };
             let line = self.line;
-            let mut sprites: Vec<(usize, &Sprite)> = self.oam.iter()
+            let mut sprites: Vec<(usize, Sprite)> = self.oam.iter()
                          .filter(|sprite| {
                             let y = sprite.y as i32 - 16;
                             y <= line as i32 && y + sprite_height > line as i32
                          })
                          .take(10)
+                         .cloned()
                          .enumerate()
                          .collect();
 
-            sprites.sort_by(|&(a_index, a), &(b_index, b)| {
-                let order = a.x.cmp(&b.x);
+            // Sprites drawn last end up on top. The DMG prioritizes by
+            // X coordinate first, the CGB only by OAM position.
+            let cgb = self.cgb;
+            sprites.sort_by(|&(a_index, ref a), &(b_index, ref b)| {
+                let order = if cgb { Ordering::Equal } else { a.x.cmp(&b.x) };
                 if let Ordering::Equal = order {
                     a_index.cmp(&b_index).reverse()
                 } else {
@@ -386,12 +529,6 @@ impl Gpu {
             for (_, sprite) in sprites {
                 let sprite_x = sprite.x.wrapping_sub(8);
 
-                let palette = if sprite.flags.contains(PALETTE1) {
-                    self.obj_palette1
-                } else {
-                    self.obj_palette0
-                };
-
                 let y = sprite.y as i32 - 16;
                 let y_ofs = if sprite.flags.contains(Y_FLIP) {
                     (sprite_height - 1 - (self.line as i32 - y)) as usize
@@ -399,10 +536,12 @@ impl Gpu {
                     (self.line as i32 - y) as usize
                 };
 
+                let bank = if self.cgb && sprite.flags.contains(CGB_BANK) { 1 }
+                           else { 0 };
                 let tile = if y_ofs < 8 {
-                    self.tiles[sprite.tile_idx as usize]
+                    self.tiles[bank][sprite.tile_idx as usize]
                 } else {
-                    self.tiles[(sprite.tile_idx+1) as usize]
+                    self.tiles[bank][(sprite.tile_idx+1) as usize]
                 };
 
                 for x in (0..TILE_WIDTH).rev() {
@@ -418,10 +557,16 @@ impl Gpu {
                     let color_code = tile.get_color_code(
                         x_ofs, y_ofs % TILE_HEIGHT
                     );
-                    let color = palette.get_color(color_code);
-                    let idx = self.line as usize * SCREEN_WIDTH + x_pos;
-                    if !sprite.flags.contains(BG_PRIO) || !bg_prio[x_pos] {
-                        framebuffer[idx] = color;
+                    if color_code == 0 {
+                        continue;
+                    }
+
+                    let bg_on_top = bg_codes[x_pos] != 0 && bg_enabled
+                        && (sprite.flags.contains(BG_PRIO)
+                            || bg_attrs[x_pos].contains(TILE_PRIO));
+                    if !bg_on_top {
+                        let color = self.get_sprite_color(&sprite, color_code);
+                        self.framebuffer[line_ofs + x_pos] = color;
                     }
                 }
             }
@@ -436,31 +581,48 @@ impl Gpu {
     pub fn read_tile_data(&self, addr: u16) -> u8 {
         let tile_idx = addr as usize / 16;
         let data_idx = addr as usize % 16;
-        self.tiles[tile_idx].data[data_idx]
+        self.tiles[self.vram_bank][tile_idx].data[data_idx]
     }
 
     pub fn write_tile_data(&mut self, addr: u16, value: u8) {
         let tile_idx = addr as usize / 16;
         let data_idx = addr as usize % 16;
-        self.tiles[tile_idx].data[data_idx] = value;
+        self.tiles[self.vram_bank][tile_idx].data[data_idx] = value;
+    }
+
+    // VRAM bank 1 holds the tile attributes in place of the tile maps
+    fn tile_map_bank(&mut self, map: usize) -> &mut [u8; TILE_MAP_SIZE] {
+        if self.vram_bank == 0 {
+            &mut self.tile_map[map]
+        } else {
+            &mut self.tile_attrs[map]
+        }
     }
 
     // 0x9800-0x9BFF
     pub fn read_tile_map1(&self, addr: u16) -> u8 {
-        self.tile_map[0][addr as usize]
+        if self.vram_bank == 0 {
+            self.tile_map[0][addr as usize]
+        } else {
+            self.tile_attrs[0][addr as usize]
+        }
     }
 
     pub fn write_tile_map1(&mut self, addr: u16, value: u8) {
-        self.tile_map[0][addr as usize] = value;
+        self.tile_map_bank(0)[addr as usize] = value;
     }
 
     // 0x9C00-0x9FFF
     pub fn read_tile_map2(&self, addr: u16) -> u8 {
-        self.tile_map[1][addr as usize]
+        if self.vram_bank == 0 {
+            self.tile_map[1][addr as usize]
+        } else {
+            self.tile_attrs[1][addr as usize]
+        }
     }
 
     pub fn write_tile_map2(&mut self, addr: u16, value: u8) {
-        self.tile_map[1][addr as usize] = value;
+        self.tile_map_bank(1)[addr as usize] = value;
     }
 
     // Sprites: 0xFE00-0xFE9F
@@ -586,6 +748,139 @@ impl Gpu {
         self.window_x = value;
     }
 
+    // IO: 0xFF4F (CGB only)
+    pub fn read_vram_bank_reg(&self) -> u8 {
+        0xFE | self.vram_bank as u8
+    }
+
+    pub fn write_vram_bank_reg(&mut self, value: u8) {
+        self.vram_bank = (value & 0x01) as usize;
+    }
+
+    // IO: 0xFF68 (CGB only)
+    pub fn read_bg_palette_index_reg(&self) -> u8 {
+        self.bg_color_palette.read_index()
+    }
+
+    pub fn write_bg_palette_index_reg(&mut self, value: u8) {
+        self.bg_color_palette.write_index(value);
+    }
+
+    // IO: 0xFF69 (CGB only)
+    pub fn read_bg_palette_data_reg(&self) -> u8 {
+        self.bg_color_palette.read_data()
+    }
+
+    pub fn write_bg_palette_data_reg(&mut self, value: u8) {
+        self.bg_color_palette.write_data(value);
+    }
+
+    // IO: 0xFF6A (CGB only)
+    pub fn read_obj_palette_index_reg(&self) -> u8 {
+        self.obj_color_palette.read_index()
+    }
+
+    pub fn write_obj_palette_index_reg(&mut self, value: u8) {
+        self.obj_color_palette.write_index(value);
+    }
+
+    // IO: 0xFF6B (CGB only)
+    pub fn read_obj_palette_data_reg(&self) -> u8 {
+        self.obj_color_palette.read_data()
+    }
+
+    pub fn write_obj_palette_data_reg(&mut self, value: u8) {
+        self.obj_color_palette.write_data(value);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.mode as u8);
+        writer.write_u32(self.clock);
+        writer.write_u8(self.line);
+        writer.write_u8(self.line_match_reg);
+
+        writer.write_u8(self.lcdc_reg.bits);
+        writer.write_u8(self.stat_reg.bits);
+
+        writer.write_u8(self.scroll_x);
+        writer.write_u8(self.scroll_y);
+        writer.write_u8(self.window_x);
+        writer.write_u8(self.window_y);
+
+        writer.write_u8(self.bg_palette.data);
+        writer.write_u8(self.obj_palette0.data);
+        writer.write_u8(self.obj_palette1.data);
+
+        writer.write_u8(self.vram_bank as u8);
+        for palette in [self.bg_color_palette, self.obj_color_palette].iter() {
+            writer.write_bytes(&palette.data);
+            writer.write_u8(palette.read_index());
+        }
+
+        for bank in self.tiles.iter() {
+            for tile in bank.iter() {
+                writer.write_bytes(&tile.data);
+            }
+        }
+        for map in 0..2 {
+            writer.write_bytes(&self.tile_map[map]);
+            writer.write_bytes(&self.tile_attrs[map]);
+        }
+
+        for addr in 0..(NUM_SPRITES * OAM_ENTRY_SIZE) as u16 {
+            writer.write_u8(self.read_oam(addr));
+        }
+
+        for color in self.framebuffer.iter() {
+            writer.write_u16(*color);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.mode = GpuMode::from_bits(try!(reader.read_u8()));
+        self.clock = try!(reader.read_u32());
+        self.line = try!(reader.read_u8());
+        self.line_match_reg = try!(reader.read_u8());
+
+        self.lcdc_reg = LCDCFlags::from_bits_truncate(try!(reader.read_u8()));
+        self.stat_reg = StatFlags::from_bits_truncate(try!(reader.read_u8()));
+
+        self.scroll_x = try!(reader.read_u8());
+        self.scroll_y = try!(reader.read_u8());
+        self.window_x = try!(reader.read_u8());
+        self.window_y = try!(reader.read_u8());
+
+        self.bg_palette.data = try!(reader.read_u8());
+        self.obj_palette0.data = try!(reader.read_u8());
+        self.obj_palette1.data = try!(reader.read_u8());
+
+        self.vram_bank = (try!(reader.read_u8()) & 0x01) as usize;
+        for palette in [&mut self.bg_color_palette, &mut self.obj_color_palette].iter_mut() {
+            try!(reader.read_bytes(&mut palette.data));
+            palette.write_index(try!(reader.read_u8()));
+        }
+
+        for bank in self.tiles.iter_mut() {
+            for tile in bank.iter_mut() {
+                try!(reader.read_bytes(&mut tile.data));
+            }
+        }
+        for map in 0..2 {
+            try!(reader.read_bytes(&mut self.tile_map[map]));
+            try!(reader.read_bytes(&mut self.tile_attrs[map]));
+        }
+
+        for addr in 0..(NUM_SPRITES * OAM_ENTRY_SIZE) as u16 {
+            let value = try!(reader.read_u8());
+            self.write_oam(addr, value);
+        }
+
+        for color in self.framebuffer.iter_mut() {
+            *color = try!(reader.read_u16()) & 0x7FFF;
+        }
+        Ok(())
+    }
+
     // Debugging helpers
 
     #[allow(dead_code)]
@@ -611,16 +906,98 @@ impl Gpu {
     fn print_framebuffer(&self) {
         for i in 0..SCREEN_HEIGHT {
             for j in 0..SCREEN_WIDTH {
-                let ch = match self.framebuffer[i*SCREEN_WIDTH+j] {
-                    Color::Black => '■',
-                    Color::DarkGray => '▩',
-                    Color::LightGray => '▥',
-                    Color::White => ' ',
-                };
-                print!("{}", ch);
+                print!("{}", shade_char(self.framebuffer[i*SCREEN_WIDTH+j]));
             }
             print!("\n");
         }
         print!("\n");
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Gpu, SCREEN_WIDTH};
+
+    const WHITE: u16 = 0x7FFF;
+    const LIGHT_GRAY: u16 = 0x6318;
+    const BLACK: u16 = 0x0000;
+
+    // Display, background and sprites on, tiles at 0x8000, map at 0x9800
+    fn create_gpu() -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.write_lcdc_reg(0x93);
+        gpu.write_bg_palette_reg(0xE4);
+        gpu.write_obj_palette0_reg(0xE4);
+        gpu
+    }
+
+    // Sets the first row of a tile to the given low and high bit planes
+    fn write_tile_row(gpu: &mut Gpu, tile: u16, lo: u8, hi: u8) {
+        gpu.write_tile_data(tile * 16, lo);
+        gpu.write_tile_data(tile * 16 + 1, hi);
+    }
+
+    fn write_sprite(gpu: &mut Gpu, idx: u16, x: u8, tile: u8, flags: u8) {
+        gpu.write_oam(idx * 4, 16);
+        gpu.write_oam(idx * 4 + 1, x);
+        gpu.write_oam(idx * 4 + 2, tile);
+        gpu.write_oam(idx * 4 + 3, flags);
+    }
+
+    fn line(gpu: &Gpu) -> &[u16] {
+        &gpu.get_framebuffer()[..SCREEN_WIDTH]
+    }
+
+    #[test]
+    fn test_fine_scroll() {
+        let mut gpu = create_gpu();
+        // Only the fourth pixel of the first tile is set
+        write_tile_row(&mut gpu, 1, 0x10, 0x00);
+        gpu.write_tile_map1(0, 1);
+
+        gpu.write_scroll_x_reg(3);
+        gpu.render_line();
+        assert_eq!(&line(&gpu)[..4], &[LIGHT_GRAY, WHITE, WHITE, WHITE]);
+
+        // The last pixels on screen wrap around to the start of the map
+        gpu.write_scroll_x_reg(0xFE);
+        gpu.render_line();
+        assert_eq!(line(&gpu)[5], LIGHT_GRAY);
+        assert_eq!(line(&gpu)[1], WHITE);
+    }
+
+    #[test]
+    fn test_sprite_transparency() {
+        let mut gpu = create_gpu();
+        write_tile_row(&mut gpu, 1, 0xFF, 0x00);
+        gpu.write_tile_map1(0, 1);
+        // Color 3 on the right half, transparent color 0 on the left
+        write_tile_row(&mut gpu, 2, 0x0F, 0x0F);
+        write_sprite(&mut gpu, 0, 8, 2, 0x00);
+
+        gpu.render_line();
+        assert_eq!(&line(&gpu)[..8], &[LIGHT_GRAY, LIGHT_GRAY, LIGHT_GRAY, LIGHT_GRAY,
+                                       BLACK, BLACK, BLACK, BLACK]);
+    }
+
+    #[test]
+    fn test_sprite_behind_bg() {
+        let mut gpu = create_gpu();
+        // BG color 1 on the left half, color 0 on the right. BGP shows
+        // color 0 as light gray, which doesn't give it priority.
+        gpu.write_bg_palette_reg(0xE5);
+        write_tile_row(&mut gpu, 1, 0xF0, 0x00);
+        gpu.write_tile_map1(0, 1);
+        write_tile_row(&mut gpu, 2, 0xFF, 0xFF);
+        write_sprite(&mut gpu, 0, 8, 2, 0x80);
+
+        gpu.render_line();
+        assert_eq!(&line(&gpu)[..8], &[LIGHT_GRAY, LIGHT_GRAY, LIGHT_GRAY, LIGHT_GRAY,
+                                       BLACK, BLACK, BLACK, BLACK]);
+
+        // Without the priority flag, the sprite covers everything
+        write_sprite(&mut gpu, 0, 8, 2, 0x00);
+        gpu.render_line();
+        assert_eq!(&line(&gpu)[..8], &[BLACK; 8]);
+    }
+}
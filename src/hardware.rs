@@ -13,16 +13,30 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::cmp;
+
 use mem_map;
 use memory;
 use timer;
 use gpu;
+use apu;
 use int_controller::{self, Interrupt};
 use events;
 use cartridge;
+use error::Error;
 use joypad;
+use serial;
+use state::{StateReader, StateWriter};
+
+const CGB_BIOS_SIZE: usize = 0x900;
+// The CPU is stopped for this many cycles while switching speed
+const SPEED_SWITCH_CYCLES: u16 = 2050;
 
 mod dma {
+    use std::cmp;
+    use error::Error;
+    use state::{StateReader, StateWriter};
+
     #[derive(Copy, Clone, Debug)]
     enum DmaState {
         Inactive,
@@ -98,6 +112,156 @@ mod dma {
             self.clock = new_clock;
             res
         }
+
+        pub fn save_state(&self, writer: &mut StateWriter) {
+            writer.write_u8(self.state as u8);
+            writer.write_u16(self.source);
+            writer.write_u64(self.clock);
+        }
+
+        pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+            use self::DmaState::*;
+
+            self.state = match try!(reader.read_u8()) {
+                0 => Inactive,
+                1 => Requested,
+                2 => Starting,
+                3 => Copying,
+                4 => Ending,
+                _ => return Err(Error::BadState("invalid DMA state".to_string()))
+            };
+            self.source = try!(reader.read_u16());
+            self.clock = try!(reader.read_u64());
+            Ok(())
+        }
+    }
+
+    // The CPU is stalled for 8 cycles per 16 byte block
+    const HDMA_BLOCK_CYCLES: u16 = 8;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum HdmaState {
+        Inactive,
+        General,
+        HBlank,
+    }
+
+    // CGB VRAM DMA, either all at once (general purpose) or 16 bytes per HBlank
+    pub struct Hdma {
+        state: HdmaState,
+        source: u16,
+        dest: u16,
+        // Remaining blocks minus one, bit 7 is set when no transfer is active
+        length: u8,
+        stall: u16,
+    }
+
+    impl Hdma {
+        pub fn new() -> Hdma {
+            Hdma {
+                state: HdmaState::Inactive,
+                source: 0x0000,
+                dest: 0x0000,
+                length: 0xFF,
+                stall: 0,
+            }
+        }
+
+        pub fn is_general(&self) -> bool {
+            self.state == HdmaState::General
+        }
+
+        pub fn is_hblank(&self) -> bool {
+            self.state == HdmaState::HBlank
+        }
+
+        // IO: 0xFF51
+        pub fn write_source_hi(&mut self, value: u8) {
+            self.source = (self.source & 0x00F0) | ((value as u16) << 8);
+        }
+
+        // IO: 0xFF52
+        pub fn write_source_lo(&mut self, value: u8) {
+            self.source = (self.source & 0xFF00) | (value & 0xF0) as u16;
+        }
+
+        // IO: 0xFF53
+        pub fn write_dest_hi(&mut self, value: u8) {
+            self.dest = (self.dest & 0x00F0) | (((value & 0x1F) as u16) << 8);
+        }
+
+        // IO: 0xFF54
+        pub fn write_dest_lo(&mut self, value: u8) {
+            self.dest = (self.dest & 0x1F00) | (value & 0xF0) as u16;
+        }
+
+        // IO: 0xFF55
+        pub fn read_control(&self) -> u8 {
+            self.length
+        }
+
+        pub fn write_control(&mut self, value: u8) {
+            if self.state == HdmaState::HBlank && value & 0x80 == 0 {
+                // Cancel the running HBlank transfer, keeping the remaining length
+                self.state = HdmaState::Inactive;
+                self.length |= 0x80;
+                return;
+            }
+
+            self.length = value & 0x7F;
+            self.state = if value & 0x80 != 0 {
+                HdmaState::HBlank
+            } else {
+                HdmaState::General
+            };
+        }
+
+        // Returns the source and VRAM destination of the next block to copy
+        pub fn next_block(&mut self, double_speed: bool) -> (u16, u16) {
+            let block = (self.source, 0x8000 | self.dest);
+            self.source = self.source.wrapping_add(0x10);
+            self.dest = (self.dest + 0x10) & 0x1FF0;
+            // The copy takes the same time at both speeds, so the CPU misses twice the cycles
+            self.stall += if double_speed { 2 * HDMA_BLOCK_CYCLES } else { HDMA_BLOCK_CYCLES };
+
+            if self.length == 0 {
+                self.state = HdmaState::Inactive;
+                self.length = 0xFF;
+            } else {
+                self.length -= 1;
+            }
+            block
+        }
+
+        pub fn take_stall(&mut self) -> u8 {
+            let cycles = cmp::min(self.stall, 0xFF);
+            self.stall -= cycles;
+            cycles as u8
+        }
+
+        pub fn save_state(&self, writer: &mut StateWriter) {
+            writer.write_u8(self.state as u8);
+            writer.write_u16(self.source);
+            writer.write_u16(self.dest);
+            writer.write_u8(self.length);
+            writer.write_u16(self.stall);
+        }
+
+        pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+            use self::HdmaState::*;
+
+            self.state = match try!(reader.read_u8()) {
+                0 => Inactive,
+                1 => General,
+                2 => HBlank,
+                _ => return Err(Error::BadState("invalid HDMA state".to_string()))
+            };
+            self.source = try!(reader.read_u16());
+            self.dest = try!(reader.read_u16()) & 0x1FF0;
+            self.length = try!(reader.read_u8());
+            self.stall = try!(reader.read_u16());
+            Ok(())
+        }
     }
 }
 
@@ -107,37 +271,114 @@ pub trait Bus {
     fn has_irq(&self) -> bool;
     fn ack_irq(&mut self) -> Option<Interrupt>;
     fn update(&mut self, u8) -> events::Events;
+    // Advances the hardware by one cycle, right before a CPU memory access
+    fn tick(&mut self) -> events::Events;
+    fn stall_cycles(&mut self) -> u8;
+    // Returns true if STOP switched the CPU speed instead of stopping
+    fn stop(&mut self) -> bool;
+    fn is_stopped(&self) -> bool;
 }
 
 pub struct Hardware {
     memory: Box<memory::Memory>,
     gpu: gpu::Gpu,
+    apu: apu::Apu,
     timer: timer::Timer,
     joypad: joypad::Joypad,
+    serial: serial::Serial,
     int_controller: int_controller::IntController,
 
+    cgb: bool,
     bios_mapped: bool,
     bios: Box<[u8]>,
 
+    // Set by STOP, the clock of the CPU, timer and LCD is halted
+    stopped: bool,
+
+    // CGB speed switch (KEY1)
+    double_speed: bool,
+    speed_switch_armed: bool,
+    half_cycle: u8,
+    stall: u16,
+
+    // Elapsed time in normal speed cycles, independent of the CPU speed
+    clock: u64,
+
     cartridge: cartridge::Cartridge,
     dma: dma::Dma,
+    hdma: dma::Hdma,
 }
 
 impl Hardware {
-    pub fn new(bios: Box<[u8]>, cart_rom: Box<[u8]>) -> Hardware {
-        Hardware {
+    pub fn new(bios: Option<Box<[u8]>>, cart_rom: Box<[u8]>)
+        -> Result<Hardware, Error> {
+        if let Some(ref bios) = bios {
+            if bios.len() < 256 {
+                return Err(Error::InvalidBios(bios.len()));
+            }
+        }
+
+        let cartridge = try!(cartridge::Cartridge::new(cart_rom));
+
+        // A DMG boot ROM runs CGB cartridges in DMG mode, like the real hardware
+        let cgb = cartridge.header().cgb_support != cartridge::CgbSupport::None
+            && bios.as_ref().map_or(true, |bios| bios.len() >= CGB_BIOS_SIZE);
+
+        let mut hardware = Hardware {
             memory: memory::Memory::new(),
             gpu: gpu::Gpu::new(),
+            apu: apu::Apu::new(),
             timer: timer::Timer::new(),
             joypad: joypad::Joypad::new(),
+            serial: serial::Serial::new(),
             int_controller: int_controller::IntController::new(),
 
-            bios_mapped: true,
-            bios: bios,
+            cgb: cgb,
+            bios_mapped: bios.is_some(),
+            bios: bios.unwrap_or(Box::new([])),
+
+            stopped: false,
+
+            double_speed: false,
+            speed_switch_armed: false,
+            half_cycle: 0,
+            stall: 0,
+
+            clock: 0,
 
-            cartridge: cartridge::Cartridge::new(cart_rom),
+            cartridge: cartridge,
             dma: dma::Dma::new(),
+            hdma: dma::Hdma::new(),
+        };
+
+        hardware.gpu.set_cgb_mode(cgb);
+        if !hardware.bios_mapped {
+            hardware.init_post_boot();
         }
+
+        Ok(hardware)
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    // IO register state the DMG boot ROM leaves behind when jumping to 0x0100
+    fn init_post_boot(&mut self) {
+        self.joypad.write_joypad_reg(0x30);
+        self.timer.set_system_counter(0xABCC);
+        self.timer.write_control_reg(0xF8);
+        self.int_controller.write_pending_reg(0xE1);
+        self.gpu.write_lcdc_reg(0x91);
+        self.gpu.write_stat_reg(0x85);
+        self.gpu.write_bg_palette_reg(0xFC);
+        self.gpu.write_obj_palette0_reg(0xFF);
+        self.gpu.write_obj_palette1_reg(0xFF);
+        self.apu.init_post_boot();
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
@@ -145,7 +386,10 @@ impl Hardware {
 
         match mem_map::map_address(addr) {
             ROMBank0(a) => {
-                if self.bios_mapped && a < 256 {
+                // The CGB boot ROM leaves a gap for the cartridge header
+                let in_bios = a < 0x100
+                    || (self.cgb && 0x200 <= a && (a as usize) < self.bios.len());
+                if self.bios_mapped && in_bios {
                     self.bios[a as usize]
                 } else {
                     self.cartridge.read_rom_bank0(a)
@@ -167,12 +411,19 @@ impl Hardware {
                     // Joypad
                     0x00 => self.joypad.read_joypad_reg(),
 
+                    // Serial
+                    0x01 => self.serial.read_data_reg(),
+                    0x02 => self.serial.read_control_reg(),
+
                     // Timer
                     0x04 => self.timer.read_divider_reg(),
                     0x05 => self.timer.read_counter_reg(),
                     0x06 => self.timer.read_modulo_reg(),
                     0x07 => self.timer.read_control_reg(),
 
+                    // Sound
+                    0x10...0x3F => self.apu.read_reg(a),
+
                     // GPU
                     0x40 => self.gpu.read_lcdc_reg(),
                     0x41 => self.gpu.read_stat_reg(),
@@ -186,6 +437,20 @@ impl Hardware {
                     0x4A => self.gpu.read_window_y_reg(),
                     0x4B => self.gpu.read_window_x_reg(),
 
+                    // CGB
+                    0x4F if self.cgb => self.gpu.read_vram_bank_reg(),
+                    0x68 if self.cgb => self.gpu.read_bg_palette_index_reg(),
+                    0x69 if self.cgb => self.gpu.read_bg_palette_data_reg(),
+                    0x6A if self.cgb => self.gpu.read_obj_palette_index_reg(),
+                    0x6B if self.cgb => self.gpu.read_obj_palette_data_reg(),
+                    0x70 if self.cgb => self.memory.read_ram_bank_reg(),
+                    0x4D if self.cgb => {
+                        let speed = if self.double_speed { 0x80 } else { 0x00 };
+                        0x7E | speed | self.speed_switch_armed as u8
+                    },
+                    0x55 if self.cgb => self.hdma.read_control(),
+                    0x4D | 0x4F | 0x51...0x55 | 0x68...0x6B | 0x70 => 0xff,
+
                     // Interrupts
                     0x0f => self.int_controller.read_pending_reg(),
                     0xff => self.int_controller.read_enabled_reg(),
@@ -216,12 +481,19 @@ impl Hardware {
                     // Joypad
                     0x00 => self.joypad.write_joypad_reg(value),
 
+                    // Serial
+                    0x01 => self.serial.write_data_reg(value),
+                    0x02 => self.serial.write_control_reg(value),
+
                     // Timer
                     0x04 => self.timer.write_divider_reg(value),
                     0x05 => self.timer.write_counter_reg(value),
                     0x06 => self.timer.write_modulo_reg(value),
                     0x07 => self.timer.write_control_reg(value),
 
+                    // Sound
+                    0x10...0x3F => self.apu.write_reg(a, value),
+
                     // GPU
                     0x40 => self.gpu.write_lcdc_reg(value),
                     0x41 => self.gpu.write_stat_reg(value),
@@ -241,6 +513,29 @@ impl Hardware {
                     0x4A => self.gpu.write_window_y_reg(value),
                     0x4B => self.gpu.write_window_x_reg(value),
 
+                    // CGB
+                    0x4D if self.cgb => self.speed_switch_armed = value & 0x01 != 0,
+                    0x4F if self.cgb => self.gpu.write_vram_bank_reg(value),
+                    0x51 if self.cgb => self.hdma.write_source_hi(value),
+                    0x52 if self.cgb => self.hdma.write_source_lo(value),
+                    0x53 if self.cgb => self.hdma.write_dest_hi(value),
+                    0x54 if self.cgb => self.hdma.write_dest_lo(value),
+                    0x55 if self.cgb => {
+                        self.hdma.write_control(value);
+                        while self.hdma.is_general() {
+                            self.copy_hdma_block();
+                        }
+                        // A transfer started during HBlank copies its first block right away
+                        if self.hdma.is_hblank() && self.gpu.in_hblank() {
+                            self.copy_hdma_block();
+                        }
+                    },
+                    0x68 if self.cgb => self.gpu.write_bg_palette_index_reg(value),
+                    0x69 if self.cgb => self.gpu.write_bg_palette_data_reg(value),
+                    0x6A if self.cgb => self.gpu.write_obj_palette_index_reg(value),
+                    0x6B if self.cgb => self.gpu.write_obj_palette_data_reg(value),
+                    0x70 if self.cgb => self.memory.write_ram_bank_reg(value),
+
                     // Unmap bios
                     0x50 if value != 0 => self.bios_mapped = false,
 
@@ -254,10 +549,26 @@ impl Hardware {
         }
     }
 
+    fn copy_hdma_block(&mut self) {
+        let (source, dest) = self.hdma.next_block(self.double_speed);
+        for i in 0..0x10 {
+            let value = self.read_byte(source.wrapping_add(i));
+            self.write_byte(dest + i, value);
+        }
+    }
+
     pub fn framebuffer(&self) -> &gpu::Framebuffer {
         self.gpu.get_framebuffer()
     }
 
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        self.apu.drain_samples(out);
+    }
+
     pub fn press_key(&mut self, key: joypad::Key) {
         self.joypad.key_pressed(key, &mut self.int_controller);
     }
@@ -265,6 +576,72 @@ impl Hardware {
     pub fn release_key(&mut self, key: joypad::Key) {
         self.joypad.key_released(key);
     }
+
+    pub fn set_rtc_host_sync(&mut self, enabled: bool) {
+        self.cartridge.set_rtc_host_sync(enabled);
+    }
+
+    pub fn set_serial_device(&mut self, device: Option<Box<serial::SerialDevice>>) {
+        self.serial.set_device(device);
+    }
+
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.serial.take_captured()
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.cartridge.rumble()
+    }
+
+    pub fn cartridge(&mut self) -> &mut cartridge::Cartridge {
+        &mut self.cartridge
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.memory.save_state(writer);
+        self.gpu.save_state(writer);
+        self.apu.save_state(writer);
+        self.timer.save_state(writer);
+        self.joypad.save_state(writer);
+        self.serial.save_state(writer);
+        self.int_controller.save_state(writer);
+        writer.write_bool(self.bios_mapped);
+        writer.write_bool(self.stopped);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_u8(self.half_cycle);
+        writer.write_u16(self.stall);
+        writer.write_u64(self.clock);
+        self.cartridge.save_state(writer);
+        self.dma.save_state(writer);
+        self.hdma.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        try!(self.memory.load_state(reader));
+        try!(self.gpu.load_state(reader));
+        try!(self.apu.load_state(reader));
+        try!(self.timer.load_state(reader));
+        try!(self.joypad.load_state(reader));
+        try!(self.serial.load_state(reader));
+        try!(self.int_controller.load_state(reader));
+
+        self.bios_mapped = try!(reader.read_bool());
+        if self.bios_mapped && self.bios.is_empty() {
+            return Err(Error::BadState("state was made during the BIOS, \
+                                        but no BIOS is loaded".to_string()));
+        }
+        self.stopped = try!(reader.read_bool());
+        self.double_speed = try!(reader.read_bool());
+        self.speed_switch_armed = try!(reader.read_bool());
+        self.half_cycle = try!(reader.read_u8()) & 0x01;
+        self.stall = try!(reader.read_u16());
+        self.clock = try!(reader.read_u64());
+
+        try!(self.cartridge.load_state(reader));
+        try!(self.dma.load_state(reader));
+        self.hdma.load_state(reader)
+    }
 }
 
 impl Bus for Hardware {
@@ -294,6 +671,27 @@ impl Bus for Hardware {
     }
 
     fn update(&mut self, cycles: u8) -> events::Events {
+        // In double speed mode, the timer, serial port and OAM DMA run along
+        // with the CPU, while the GPU, APU and cartridge clock keep their rate
+        let fixed_cycles = if self.double_speed {
+            let total = cycles as u16 + self.half_cycle as u16;
+            self.half_cycle = (total % 2) as u8;
+            (total / 2) as u8
+        } else {
+            cycles
+        };
+        self.clock += fixed_cycles as u64;
+
+        // The cartridge clock has its own oscillator and keeps running during STOP
+        let events = self.cartridge.tick(fixed_cycles);
+        if self.stopped {
+            // Any pressed key in the selected joypad lines wakes the system up
+            if self.joypad.read_joypad_reg() & 0x0F == 0x0F {
+                return events;
+            }
+            self.stopped = false;
+        }
+
         if self.dma.is_active() {
             let (source, ofs, len) = self.dma.tick(cycles);
 
@@ -304,7 +702,141 @@ impl Bus for Hardware {
         }
 
         self.timer.tick(cycles, &mut self.int_controller);
+        self.apu.step(fixed_cycles);
+        self.serial.step(cycles, &mut self.int_controller);
+        let events = events | self.gpu.step(fixed_cycles, &mut self.int_controller);
+
+        if self.gpu.take_hblank_start() && self.hdma.is_hblank() {
+            self.copy_hdma_block();
+        }
+        events
+    }
+
+    fn tick(&mut self) -> events::Events {
+        self.update(1)
+    }
+
+    fn stall_cycles(&mut self) -> u8 {
+        if self.stall != 0 {
+            let cycles = cmp::min(self.stall, 0xFF);
+            self.stall -= cycles;
+            return cycles as u8;
+        }
+        self.hdma.take_stall()
+    }
+
+    fn stop(&mut self) -> bool {
+        self.timer.write_divider_reg(0);
+        if !self.speed_switch_armed {
+            self.stopped = true;
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.stall = SPEED_SWITCH_CYCLES;
+        true
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bus, Hardware};
+
+    fn create_hardware() -> Hardware {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut hardware = Hardware::new(None, rom.into_boxed_slice()).unwrap();
+
+        for i in 0..0x100 {
+            hardware.write_byte(0xC000 + i, (i + 1) as u8);
+        }
+        hardware.write_byte(0xFF51, 0xC0);
+        hardware.write_byte(0xFF52, 0x00);
+        hardware.write_byte(0xFF53, 0x80);
+        hardware.write_byte(0xFF54, 0x00);
+        hardware
+    }
 
-        self.gpu.step(cycles, &mut self.int_controller)
+    // Number of bytes at the start of VRAM that match the source pattern
+    fn copied_bytes(hardware: &Hardware) -> u16 {
+        (0..0x100).take_while(|&i| hardware.read_byte(0x8000 + i) == (i + 1) as u8)
+            .count() as u16
     }
-}
\ No newline at end of file
+
+    fn total_stall(hardware: &mut Hardware) -> u32 {
+        let mut total = 0;
+        loop {
+            match hardware.stall_cycles() {
+                0 => return total,
+                cycles => total += cycles as u32
+            }
+        }
+    }
+
+    // Steps until the GPU enters the next HBlank
+    fn next_hblank(hardware: &mut Hardware) {
+        while hardware.gpu.in_hblank() {
+            hardware.update(1);
+        }
+        while !hardware.gpu.in_hblank() {
+            hardware.update(1);
+        }
+    }
+
+    #[test]
+    fn test_general_dma() {
+        let mut hardware = create_hardware();
+        hardware.write_byte(0xFF55, 0x03);
+        assert_eq!(hardware.read_byte(0xFF55), 0xFF);
+        assert_eq!(copied_bytes(&hardware), 4 * 0x10);
+        assert_eq!(total_stall(&mut hardware), 4 * 8);
+
+        // The copy takes twice the CPU cycles in double speed mode
+        let mut hardware = create_hardware();
+        hardware.double_speed = true;
+        hardware.write_byte(0xFF55, 0x03);
+        assert_eq!(copied_bytes(&hardware), 4 * 0x10);
+        assert_eq!(total_stall(&mut hardware), 4 * 16);
+    }
+
+    #[test]
+    fn test_hblank_dma() {
+        let mut hardware = create_hardware();
+        assert!(!hardware.gpu.in_hblank());
+        hardware.write_byte(0xFF55, 0x82);
+        assert_eq!(hardware.read_byte(0xFF55), 0x02);
+        assert_eq!(copied_bytes(&hardware), 0);
+
+        for block in 1..4 {
+            next_hblank(&mut hardware);
+            assert_eq!(copied_bytes(&hardware), block * 0x10);
+            assert_eq!(total_stall(&mut hardware), 8);
+        }
+        assert_eq!(hardware.read_byte(0xFF55), 0xFF);
+
+        next_hblank(&mut hardware);
+        assert_eq!(copied_bytes(&hardware), 0x30);
+    }
+
+    #[test]
+    fn test_hblank_dma_cancel() {
+        let mut hardware = create_hardware();
+        hardware.write_byte(0xFF55, 0x85);
+        next_hblank(&mut hardware);
+        next_hblank(&mut hardware);
+        assert_eq!(copied_bytes(&hardware), 0x20);
+
+        // Four blocks are left, the register holds the count minus one
+        hardware.write_byte(0xFF55, 0x00);
+        assert_eq!(hardware.read_byte(0xFF55), 0x83);
+
+        next_hblank(&mut hardware);
+        assert_eq!(copied_bytes(&hardware), 0x20);
+        assert_eq!(hardware.read_byte(0xFF55), 0x83);
+    }
+}
@@ -0,0 +1,196 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use error::Error;
+use events;
+use gameboy::{Gameboy, CYCLES_PER_FRAME};
+use gpu::{self, SCREEN_WIDTH, SCREEN_HEIGHT};
+use joypad::Key;
+use png;
+
+// Runs the emulator without any video or audio device, for automated tests.
+//
+// The input script has one event per line: the frame number, `press` or
+// `release` and the key name, e.g. `120 press start`. Empty lines and
+// lines starting with '#' are ignored.
+pub fn run(gameboy: &mut Gameboy, cycles: u64, input_path: Option<&str>,
+           screenshot_path: Option<&str>) -> Result<(), Error> {
+    let mut inputs = match input_path {
+        Some(path) => try!(read_input_script(path)),
+        None => Vec::new()
+    };
+    inputs.sort_by_key(|input| input.frame);
+
+    for input in inputs {
+        let target = input.frame * CYCLES_PER_FRAME;
+        if target > cycles {
+            break;
+        }
+        run_until(gameboy, target);
+        if input.pressed {
+            gameboy.press_key(input.key);
+        } else {
+            gameboy.release_key(input.key);
+        }
+    }
+    run_until(gameboy, cycles);
+
+    let serial_output = gameboy.take_serial_output();
+    if !serial_output.is_empty() {
+        println!("Serial output:\n{}", String::from_utf8_lossy(&serial_output));
+    }
+
+    let rgb = framebuffer_rgb(gameboy);
+    println!("Cycles: {}", gameboy.total_cycles());
+    println!("Framebuffer hash: {:016x}", fnv1a(&rgb));
+
+    if let Some(path) = screenshot_path {
+        try!(write_screenshot(path, &rgb)
+             .map_err(|why| Error::Io(String::from(path), why)));
+    }
+    Ok(())
+}
+
+fn run_until(gameboy: &mut Gameboy, cycles: u64) {
+    while gameboy.total_cycles() < cycles {
+        let (_, events) = gameboy.simulate(cycles);
+        if events.contains(events::LOCKED) {
+            println!("CPU locked up at {:#06x}", gameboy.pc());
+        }
+    }
+}
+
+fn framebuffer_rgb(gameboy: &mut Gameboy) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+    for color in gameboy.framebuffer().iter() {
+        let (r, g, b) = gpu::to_rgb(*color);
+        rgb.extend_from_slice(&[r, g, b]);
+    }
+    rgb
+}
+
+// 64-bit FNV-1a, stable across platforms and builds
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// The format is chosen by the extension, PPM for .ppm and PNG otherwise
+fn write_screenshot(path: &str, rgb: &[u8]) -> ::std::io::Result<()> {
+    let mut file = BufWriter::new(try!(File::create(path)));
+    let is_ppm = Path::new(path).extension().map_or(false, |ext| ext == "ppm");
+    if is_ppm {
+        try!(write!(file, "P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT));
+        try!(file.write_all(rgb));
+    } else {
+        try!(png::write_rgb(&mut file, SCREEN_WIDTH, SCREEN_HEIGHT, rgb));
+    }
+    file.flush()
+}
+
+struct Input {
+    frame: u64,
+    key: Key,
+    pressed: bool,
+}
+
+fn read_input_script(path: &str) -> Result<Vec<Input>, Error> {
+    let mut script = String::new();
+    try!(File::open(path)
+         .and_then(|mut file| file.read_to_string(&mut script))
+         .map_err(|why| Error::Io(String::from(path), why)));
+
+    let mut inputs = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let input = try!(parse_input(line)
+                         .map_err(|why| Error::BadInputScript(i + 1, why)));
+        inputs.push(input);
+    }
+    Ok(inputs)
+}
+
+fn parse_input(line: &str) -> Result<Input, String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err("expected '<frame> press|release <key>'".to_string());
+    }
+
+    let frame = try!(parts[0].parse::<u64>()
+                     .map_err(|_| format!("invalid frame number '{}'", parts[0])));
+    let pressed = match parts[1] {
+        "press" => true,
+        "release" => false,
+        action => return Err(format!("unknown action '{}'", action))
+    };
+    let key = match &*parts[2].to_lowercase() {
+        "right"  => Key::Right,
+        "left"   => Key::Left,
+        "up"     => Key::Up,
+        "down"   => Key::Down,
+        "a"      => Key::A,
+        "b"      => Key::B,
+        "select" => Key::Select,
+        "start"  => Key::Start,
+        key => return Err(format!("unknown key '{}'", key))
+    };
+
+    Ok(Input {
+        frame: frame,
+        key: key,
+        pressed: pressed,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_input;
+    use joypad::Key;
+
+    #[test]
+    fn test_parse_input() {
+        let input = parse_input("120 press start").unwrap();
+        assert_eq!(input.frame, 120);
+        assert!(input.pressed);
+        assert!(match input.key { Key::Start => true, _ => false });
+
+        let input = parse_input("0  release   A").unwrap();
+        assert_eq!(input.frame, 0);
+        assert!(!input.pressed);
+        assert!(match input.key { Key::A => true, _ => false });
+    }
+
+    #[test]
+    fn test_parse_input_malformed() {
+        assert!(parse_input("").is_err());
+        assert!(parse_input("120 press").is_err());
+        assert!(parse_input("120 press start now").is_err());
+        assert!(parse_input("-1 press start").is_err());
+        assert!(parse_input("start press 120").is_err());
+        assert!(parse_input("120 hold start").is_err());
+        assert!(parse_input("120 press turbo").is_err());
+    }
+}
@@ -88,7 +88,8 @@ for code in range(0, 256):
 
     if lo == 0 and 0 <= hi <= 1:
         op = ["nop", "stop"][hi]
-        cycles = 1
+        imm = [none, imm8][hi] # STOP is followed by a padding byte
+        cycles = [1, 2][hi]
     elif lo == 0 and 2 <= hi <= 3:
         op = "jp_rel_cond"
         cond = ["NZ", "NC"][hi-2]
@@ -252,7 +253,7 @@ for code in range(0, 256):
         op = "pop16"
         dest = [bc, de, hl, af][hi-0xC]
         dtype = reg16
-        cycles = 2
+        cycles = 3
 
     elif lo == 2 and 0xC <= hi <= 0xD:
         op = "jp_cond"
@@ -44,7 +44,7 @@ pub const OPCODES: [(Op, Immediate); 256] = [
     (dec8_reg { src: Reg8::C }, None), //0x0D
     (ld8_imm { dest: Reg8::C }, IMM8), //0x0E
     (rrca, None), //0x0F
-    (stop, None), //0x10
+    (stop, IMM8), //0x10
     (ld16_imm { dest: Reg16::DE }, IMM16), //0x11
     (st8_ind { dest: Addr::DE, src: Reg8::A }, None), //0x12
     (inc16_reg { src: Reg16::DE }, None), //0x13
@@ -547,24 +547,24 @@ pub const EXT_OPCODES: [Op; 256] = [
 
 pub fn cycles(opcode: &Op) -> u8 {
     match *opcode {
-        nop | inc8_reg {..} | dec8_reg {..} | rlca | rrca | stop 
-        | rla | rra | daa | cpl | scf | ccf | ld8_rr {..} | halt | add8_reg {..} 
+        nop | inc8_reg {..} | dec8_reg {..} | rlca | rrca | rla | rra 
+        | daa | cpl | scf | ccf | ld8_rr {..} | halt | add8_reg {..} 
         | adc8_reg {..} | sub8_reg {..} | sbc8_reg {..} | and8_reg {..} 
         | xor8_reg {..} | or8_reg {..} | cp8_reg {..} | di | ei => 1,
         st8_ind { dest: Addr::BC, src: _ } | inc16_reg {..} | ld8_imm {..} 
         | add16_reg {..} | ld8_ind { dest: _, src: Addr::BC } | dec16_reg {..} 
-        | st8_ind { dest: Addr::DE, src: _ } | ld8_ind { dest: _, src: Addr::DE } 
+        | stop | st8_ind { dest: Addr::DE, src: _ } | ld8_ind { dest: _, src: Addr::DE } 
         | st8_ind { dest: Addr::HLI, src: _ } | ld8_ind { dest: _, src: Addr::HLI } 
         | st8_ind { dest: Addr::HLD, src: _ } | ld8_ind { dest: _, src: Addr::HLD } 
         | ld8_ind { dest: _, src: Addr::HL } | st8_ind { dest: Addr::HL, src: _ } 
         | add8_ind | adc8_ind | sub8_ind | sbc8_ind | and8_ind | xor8_ind 
-        | or8_ind | cp8_ind | pop16 {..} | add8_imm | ret_cond {..} 
-        | adc8_imm | sub8_imm | sbc8_imm | out8_reg | and8_imm | xor8_imm 
-        | in8_reg | or8_imm | ld16_sp | cp8_imm | rlc {..} | rrc {..} 
-        | rl {..} | rr {..} | sla {..} | sra {..} | swap {..} | srl {..} 
-        | bit {..} | res {..} | set {..} => 2,
-        ld16_imm {..} | inc8_ind | dec8_ind | st8_ind_imm | out8_imm 
-        | in8_imm | ld16_lea | bit_ind {..} => 3,
+        | or8_ind | cp8_ind | add8_imm | ret_cond {..} | adc8_imm 
+        | sub8_imm | sbc8_imm | out8_reg | and8_imm | xor8_imm | in8_reg 
+        | or8_imm | ld16_sp | cp8_imm | rlc {..} | rrc {..} | rl {..} 
+        | rr {..} | sla {..} | sra {..} | swap {..} | srl {..} | bit {..} 
+        | res {..} | set {..} => 2,
+        ld16_imm {..} | inc8_ind | dec8_ind | st8_ind_imm | pop16 {..} 
+        | out8_imm | in8_imm | ld16_lea | bit_ind {..} => 3,
         push16 {..} | rst {..} | add8_sp_imm | st8_ind { dest: Addr::Imm, src: _ } 
         | ld8_ind { dest: _, src: Addr::Imm } | rlc_ind | rrc_ind 
         | rl_ind | rr_ind | sla_ind | sra_ind | swap_ind | srl_ind 
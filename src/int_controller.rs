@@ -14,6 +14,9 @@
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 
+use error::Error;
+use state::{StateReader, StateWriter};
+
 #[derive(Copy, Clone, Debug)]
 pub enum Interrupt {
     VBlank          = 1 << 0,
@@ -113,4 +116,15 @@ impl IntController {
         self.ints_pending = self.ints_pending - highest_prio;
         Interrupt::from_bits(highest_prio.bits)
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.ints_enabled.bits);
+        writer.write_u8(self.ints_pending.bits);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.ints_enabled.bits = try!(reader.read_u8());
+        self.ints_pending.bits = try!(reader.read_u8());
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -14,6 +14,8 @@
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 use int_controller::{IntController, Interrupt};
+use error::Error;
+use state::{StateReader, StateWriter};
 
 #[derive(Debug)]
 pub enum Key {
@@ -108,4 +110,20 @@ impl Joypad {
             _    => unreachable!()
         }
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.keys_pressed[0].bits);
+        writer.write_u8(self.keys_pressed[1].bits);
+        writer.write_u8(self.active_column as u8);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.keys_pressed[0] = KeysPressed::from_bits_truncate(try!(reader.read_u8()));
+        self.keys_pressed[1] = KeysPressed::from_bits_truncate(try!(reader.read_u8()));
+        self.active_column = match try!(reader.read_u8()) {
+            column @ 0...3 => column as usize,
+            _ => return Err(Error::BadState("invalid joypad column".to_string()))
+        };
+        Ok(())
+    }
 }
\ No newline at end of file
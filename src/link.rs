@@ -0,0 +1,270 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use error::Error;
+use serial::SerialDevice;
+
+// Link cable between two emulator instances over a local TCP connection.
+//
+// Both sides stop every CYCLES_PER_SYNC cycles until the other one has
+// caught up, which keeps their clocks in lockstep. A byte sent by one side
+// is answered by the other when it reaches its next sync point, so a
+// transfer always lands at the same emulated time on both sides.
+
+const MAGIC: &'static [u8; 4] = b"GBLK";
+const VERSION: u8 = 1;
+
+const CYCLES_PER_SYNC: u32 = 2048;
+
+// A peer that doesn't answer for this long, e.g. because it is paused,
+// rewinding or in the debugger, counts as disconnected
+const READ_TIMEOUT_SECS: u64 = 3;
+
+// Messages are two bytes: the kind and a value
+const MSG_SYNC: u8 = 1;
+const MSG_TRANSFER: u8 = 2;
+const MSG_REPLY: u8 = 3;
+
+const ROLE_MASTER: u8 = 0;
+const ROLE_SLAVE: u8 = 1;
+
+pub struct Link {
+    stream: Option<TcpStream>,
+    clock: u32,
+    syncs_sent: u64,
+    syncs_received: u64,
+}
+
+impl Link {
+    // The listening side acts as the master during the handshake
+    pub fn listen(port: u16) -> Result<Link, Error> {
+        let addr = format!("127.0.0.1:{}", port);
+        let listener = try!(TcpListener::bind(&*addr).map_err(|why| link_error(why)));
+        println!("Waiting for link partner on {}", addr);
+        Link::accept(listener)
+    }
+
+    fn accept(listener: TcpListener) -> Result<Link, Error> {
+        let (stream, _) = try!(listener.accept().map_err(|why| link_error(why)));
+        Link::handshake(stream, ROLE_MASTER)
+    }
+
+    pub fn connect(port: u16) -> Result<Link, Error> {
+        let addr = format!("127.0.0.1:{}", port);
+        let stream = try!(TcpStream::connect(&*addr).map_err(|why| link_error(why)));
+        Link::handshake(stream, ROLE_SLAVE)
+    }
+
+    fn handshake(mut stream: TcpStream, role: u8) -> Result<Link, Error> {
+        try!(stream.set_nodelay(true).map_err(|why| link_error(why)));
+        try!(stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)))
+             .map_err(|why| link_error(why)));
+
+        let mut hello = [0; 6];
+        hello[..4].copy_from_slice(MAGIC);
+        hello[4] = VERSION;
+        hello[5] = role;
+
+        // The master speaks first, the slave answers
+        let mut peer = [0; 6];
+        let res = if role == ROLE_MASTER {
+            stream.write_all(&hello).and_then(|_| stream.read_exact(&mut peer))
+        } else {
+            stream.read_exact(&mut peer).and_then(|_| stream.write_all(&hello))
+        };
+        try!(res.map_err(|why| link_error(why)));
+
+        if &peer[..4] != MAGIC || peer[4] != VERSION {
+            return Err(Error::Link("peer is not a compatible emulator".to_string()));
+        }
+        if peer[5] == role {
+            return Err(Error::Link("both sides have the same role".to_string()));
+        }
+
+        println!("Link cable connected");
+        Ok(Link {
+            stream: Some(stream),
+            clock: 0,
+            syncs_sent: 0,
+            syncs_received: 0,
+        })
+    }
+
+    fn send(&mut self, kind: u8, value: u8) {
+        let res = match self.stream {
+            Some(ref mut stream) => stream.write_all(&[kind, value]),
+            None => return
+        };
+        if res.is_err() {
+            self.disconnect();
+        }
+    }
+
+    fn receive(&mut self) -> Option<(u8, u8)> {
+        let mut msg = [0; 2];
+        let res = match self.stream {
+            Some(ref mut stream) => stream.read_exact(&mut msg),
+            None => return None
+        };
+        match res {
+            Ok(_) => Some((msg[0], msg[1])),
+            Err(why) => {
+                if why.kind() == ErrorKind::WouldBlock || why.kind() == ErrorKind::TimedOut {
+                    println!("Link partner stopped responding");
+                }
+                self.disconnect();
+                None
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if self.stream.take().is_some() {
+            println!("Link cable disconnected");
+        }
+    }
+
+    // Waits for the peer to reach the same sync point. A transfer started by
+    // the peer in the meantime is answered with `value`, and the byte it
+    // sent is returned if we were `ready` for it. In that case the wait ends
+    // early, so the game can prepare for the next byte before the peer
+    // sends it. The following sync point catches up again.
+    fn sync(&mut self, value: u8, ready: bool) -> Option<u8> {
+        self.send(MSG_SYNC, 0);
+        self.syncs_sent += 1;
+
+        while self.syncs_received < self.syncs_sent {
+            match self.receive() {
+                Some((MSG_SYNC, _)) => self.syncs_received += 1,
+                Some((MSG_TRANSFER, peer_value)) => {
+                    if ready {
+                        self.send(MSG_REPLY, value);
+                        return Some(peer_value);
+                    }
+                    self.send(MSG_REPLY, 0xFF);
+                },
+                Some(_) => {
+                    println!("Unexpected message on link cable");
+                    self.disconnect();
+                },
+                None => break
+            }
+        }
+        None
+    }
+}
+
+impl SerialDevice for Link {
+    fn transfer(&mut self, value: u8) -> u8 {
+        self.send(MSG_TRANSFER, value);
+
+        loop {
+            match self.receive() {
+                Some((MSG_REPLY, peer_value)) => return peer_value,
+                Some((MSG_SYNC, _)) => self.syncs_received += 1,
+                // Both sides use their internal clock, nobody receives anything
+                Some((MSG_TRANSFER, _)) => self.send(MSG_REPLY, 0xFF),
+                Some(_) => {
+                    println!("Unexpected message on link cable");
+                    self.disconnect();
+                },
+                None => return 0xFF
+            }
+        }
+    }
+
+    fn update(&mut self, cycles: u8, value: u8, ready: bool) -> Option<u8> {
+        if self.stream.is_none() {
+            return None;
+        }
+
+        self.clock += cycles as u32;
+        if self.clock < CYCLES_PER_SYNC {
+            return None;
+        }
+        self.clock -= CYCLES_PER_SYNC;
+        self.sync(value, ready)
+    }
+}
+
+fn link_error(why: ::std::io::Error) -> Error {
+    Error::Link(why.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use serial::SerialDevice;
+    use super::Link;
+
+    // Returns the listening and the connecting side
+    fn connect_pair() -> (Link, Link) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let master = thread::spawn(move || Link::accept(listener));
+        let slave = Link::connect(port).unwrap();
+        (master.join().unwrap().unwrap(), slave)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (master, slave) = connect_pair();
+        assert!(master.stream.is_some());
+        assert!(slave.stream.is_some());
+    }
+
+    #[test]
+    fn test_transfer() {
+        let (mut master, mut slave) = connect_pair();
+        let thread = thread::spawn(move || slave.sync(0x99, true));
+        assert_eq!(master.transfer(0x42), 0x99);
+        assert_eq!(thread.join().unwrap(), Some(0x42));
+    }
+
+    #[test]
+    fn test_transfer_not_ready() {
+        let (mut master, mut slave) = connect_pair();
+        let thread = thread::spawn(move || (slave.sync(0x99, false), slave));
+        assert_eq!(master.transfer(0x42), 0xFF);
+        // The slave keeps waiting for the sync point after the refused transfer
+        assert_eq!(master.sync(0x00, false), None);
+        let (value, slave) = thread.join().unwrap();
+        assert_eq!(value, None);
+        assert!(slave.stream.is_some());
+    }
+
+    #[test]
+    fn test_both_internal_clock() {
+        let (mut master, mut slave) = connect_pair();
+        let thread = thread::spawn(move || slave.transfer(0x99));
+        assert_eq!(master.transfer(0x42), 0xFF);
+        assert_eq!(thread.join().unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // The slave stays connected, but never answers
+        let (mut master, _slave) = connect_pair();
+        assert_eq!(master.transfer(0x42), 0xFF);
+        assert!(master.stream.is_none());
+        assert_eq!(master.update(0xFF, 0x42, true), None);
+    }
+}
@@ -20,31 +20,60 @@ extern crate argparse;
 extern crate sdl2;
 
 use argparse::{ArgumentParser, Store, StoreTrue};
-use std::io::prelude::*;
-use std::error::Error;
-use std::fs::File;
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use error::Error;
+use serial::SerialDevice;
 
 #[macro_use]
 mod util;
 
+mod apu;
+mod audio;
 mod cartridge;
+#[cfg(all(test, feature = "conformance"))]
+mod conformance;
 mod cpu;
 mod debug;
+mod error;
 mod events;
 mod frontend;
 mod gameboy;
 mod gpu;
 mod hardware;
+mod headless;
 mod instructions;
 mod int_controller;
 mod joypad;
+mod link;
 mod mem_map;
 mod memory;
+mod png;
+mod printer;
+mod rewind;
+mod serial;
+mod state;
 mod timer;
 
 fn main() {
     let mut debug_mode = false;
-    let mut bios_path = String::from("rom.bin");
+    let mut headless = false;
+    let mut frames: u64 = 0;
+    let mut cycles: u64 = 0;
+    let mut input_path = String::new();
+    let mut screenshot_path = String::new();
+    let mut rtc_host_sync = false;
+    let mut save_path = String::new();
+    let mut no_save = false;
+    let mut bios_path = String::new();
+    let mut rewind_interval = 2;
+    let mut link_listen: u16 = 0;
+    let mut link_connect: u16 = 0;
+    let mut printer_dir = String::new();
+    let mut rewind_speed = 1;
     let mut rom_path = String::new();
 
     {
@@ -52,36 +81,170 @@ fn main() {
         ap.set_description("GBEmu - A Gameboy emulator");
         ap.refer(&mut debug_mode)
             .add_option(&["-d", "--debug"], StoreTrue, "Start in debug mode");
+        ap.refer(&mut headless)
+            .add_option(&["--headless"], StoreTrue,
+                        "Run without video and audio, ignoring battery saves");
+        ap.refer(&mut frames)
+            .add_option(&["--frames"], Store, "Headless: number of frames to run");
+        ap.refer(&mut cycles)
+            .add_option(&["--cycles"], Store, "Headless: number of cycles to run");
+        ap.refer(&mut input_path)
+            .add_option(&["--input"], Store,
+                        "Headless: input script with '<frame> press|release <key>' lines");
+        ap.refer(&mut screenshot_path)
+            .add_option(&["--screenshot"], Store,
+                        "Headless: write the final frame to this .png or .ppm file");
+        ap.refer(&mut rtc_host_sync)
+            .add_option(&["--rtc-host-time"], StoreTrue,
+                        "Sync the cartridge clock to the host time");
+        ap.refer(&mut save_path)
+            .add_option(&["--save"], Store,
+                        "Path to the battery save file (default: ROM path with .sav extension)");
+        ap.refer(&mut no_save)
+            .add_option(&["--no-save"], StoreTrue,
+                        "Don't load or store battery save files");
         ap.refer(&mut bios_path)
-            .add_option(&["--bios"], Store, "Path to Gameboy BIOS");
+            .add_option(&["--bios"], Store,
+                        "Path to Gameboy BIOS (skips the boot sequence if omitted)");
+        ap.refer(&mut rewind_interval)
+            .add_option(&["--rewind-interval"], Store,
+                        "Frames between rewind snapshots (default: 2)");
+        ap.refer(&mut rewind_speed)
+            .add_option(&["--rewind-speed"], Store,
+                        "Rewind speed relative to normal play (default: 1)");
+        ap.refer(&mut link_listen)
+            .add_option(&["--link-listen"], Store,
+                        "Wait for a link cable partner on this local port");
+        ap.refer(&mut link_connect)
+            .add_option(&["--link-connect"], Store,
+                        "Connect the link cable to a partner listening on this local port");
+        ap.refer(&mut printer_dir)
+            .add_option(&["--printer-dir"], Store,
+                        "Connect a Game Boy Printer that saves pages in this directory");
         ap.refer(&mut rom_path)
             .add_argument("ROM Path", Store, "Path to the ROM to emulate")
             .required();
         ap.parse_args_or_exit();
     }
 
-    let rom_buf = read_file(rom_path);
-    let bios_buf = read_file(bios_path);
+    let save_path = if no_save {
+        None
+    } else if save_path.is_empty() {
+        Some(Path::new(&rom_path).with_extension("sav"))
+    } else {
+        Some(PathBuf::from(save_path))
+    };
+
+    let rom_buf = match read_file(&rom_path) {
+        Ok(buf) => buf,
+        Err(why) => exit_with_error(why)
+    };
+    // Header errors are reported when the cartridge gets loaded
+    if let Ok(header) = cartridge::CartridgeHeader::parse(&rom_buf) {
+        for warning in header.warnings() {
+            println!("Warning: {}", warning);
+        }
+    }
+    let bios_buf = if bios_path.is_empty() {
+        None
+    } else {
+        match read_bios(&bios_path) {
+            Ok(buf) => Some(buf),
+            Err(why) => exit_with_error(why)
+        }
+    };
 
     if debug_mode {
-        debug::start(bios_buf, rom_buf);
+        if let Err(why) = debug::start(bios_buf, rom_buf) {
+            exit_with_error(why);
+        }
+    } else if headless {
+        if frames == 0 && cycles == 0 {
+            writeln!(io::stderr(), "Error: --headless needs --frames or --cycles").ok();
+            process::exit(1);
+        }
+        let cycles = if cycles != 0 { cycles } else { frames * gameboy::CYCLES_PER_FRAME };
+
+        let mut gb = match gameboy::Gameboy::new(bios_buf, rom_buf) {
+            Ok(gb) => gb,
+            Err(why) => exit_with_error(why)
+        };
+        gb.set_serial_device(open_serial_device(link_listen, link_connect, &printer_dir));
+
+        let input = if input_path.is_empty() { None } else { Some(&input_path[..]) };
+        let screenshot = if screenshot_path.is_empty() { None }
+                         else { Some(&screenshot_path[..]) };
+        if let Err(why) = headless::run(&mut gb, cycles, input, screenshot) {
+            exit_with_error(why);
+        }
     } else {
-        let mut gb = gameboy::Gameboy::new(bios_buf, rom_buf);
-        let mut frontend = frontend::Frontend::new();
+        let mut gb = match gameboy::Gameboy::new(bios_buf, rom_buf) {
+            Ok(gb) => gb,
+            Err(why) => exit_with_error(why)
+        };
+        gb.set_serial_device(open_serial_device(link_listen, link_connect, &printer_dir));
+        gb.set_rtc_host_sync(rtc_host_sync);
+        gb.set_save_path(save_path);
+        if let Err(why) = gb.load_save() {
+            println!("Could not load save file: {}", why);
+        }
+        let mut frontend = frontend::Frontend::new(Path::new(&rom_path));
+        frontend.set_rewind_options(rewind_interval, rewind_speed);
         frontend.run(&mut gb);
     }
 }
 
-fn read_file(path: String) -> Box<[u8]> {
-    let mut fd = match File::open(&path) {
-        Err(why) => panic!("Can't open file '{}': {}", path, why.description()),
-        Ok(f) => f
+fn open_serial_device(listen_port: u16, connect_port: u16, printer_dir: &str)
+    -> Option<Box<SerialDevice>> {
+    if !printer_dir.is_empty() {
+        if listen_port != 0 || connect_port != 0 {
+            writeln!(io::stderr(), "Error: Can't use the printer and the link cable at once").ok();
+            process::exit(1);
+        }
+        if let Err(why) = fs::create_dir_all(printer_dir) {
+            exit_with_error(Error::Io(String::from(printer_dir), why));
+        }
+        return Some(Box::new(printer::Printer::new(PathBuf::from(printer_dir))));
+    }
+
+    if listen_port != 0 && connect_port != 0 {
+        writeln!(io::stderr(), "Error: Can't listen for and connect to a link partner at once").ok();
+        process::exit(1);
+    }
+
+    let link = if listen_port != 0 {
+        link::Link::listen(listen_port)
+    } else if connect_port != 0 {
+        link::Link::connect(connect_port)
+    } else {
+        return None;
     };
 
-    let mut buf = Vec::new();
-    if let Err(why) = fd.read_to_end(&mut buf) {
-        panic!("Can't read file '{}': {}", path, why.description());
+    match link {
+        Ok(link) => Some(Box::new(link)),
+        Err(why) => exit_with_error(why)
     }
+}
+
+fn exit_with_error(why: Error) -> ! {
+    writeln!(io::stderr(), "Error: {}", why).ok();
+    process::exit(1);
+}
 
-    buf.into_boxed_slice()
+fn read_file(path: &str) -> Result<Box<[u8]>, Error> {
+    let mut fd = try!(File::open(path)
+                      .map_err(|why| Error::Io(String::from(path), why)));
+
+    let mut buf = Vec::new();
+    try!(fd.read_to_end(&mut buf)
+         .map_err(|why| Error::Io(String::from(path), why)));
+
+    Ok(buf.into_boxed_slice())
+}
+
+fn read_bios(path: &str) -> Result<Box<[u8]>, Error> {
+    if !Path::new(path).exists() {
+        return Err(Error::MissingBios(String::from(path)));
+    }
+    read_file(path)
 }
@@ -13,10 +13,19 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::cmp;
+use error::Error;
+use state::{StateReader, StateWriter};
+
+const RAM_BANK_SIZE: usize = 4096;
+const NUM_RAM_BANKS: usize = 8;
+
 pub struct Memory {
     // RAM: 0xC000-0xDFFF
     // Shadow copy of the RAM from 0xE000-0xFDFF
-    ram: [u8; 8192],
+    // Bank 0 is fixed at 0xC000, banks 1-7 are switchable at 0xD000 (CGB only)
+    ram: [u8; RAM_BANK_SIZE * NUM_RAM_BANKS],
+    ram_bank: u8,
 
     // Zero page RAM: 0xFF80-0xFFFF
     zram: [u8; 128],
@@ -25,13 +34,23 @@ pub struct Memory {
 impl Memory {
     pub fn new() -> Box<Memory> {
         Box::new(Memory {
-            ram: [0; 8192],
+            ram: [0; RAM_BANK_SIZE * NUM_RAM_BANKS],
+            ram_bank: 1,
             zram: [0; 128],
         })
     }
 
+    fn ram_index(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        if addr < RAM_BANK_SIZE {
+            addr
+        } else {
+            self.ram_bank as usize * RAM_BANK_SIZE + addr - RAM_BANK_SIZE
+        }
+    }
+
     pub fn read_ram(&self, addr: u16) -> u8 {
-        self.ram[addr as usize]
+        self.ram[self.ram_index(addr)]
     }
 
     pub fn read_zram(&self, addr: u16) -> u8 {
@@ -39,10 +58,33 @@ impl Memory {
     }
 
     pub fn write_ram(&mut self, addr: u16, value: u8) {
-        self.ram[addr as usize] = value;
+        let idx = self.ram_index(addr);
+        self.ram[idx] = value;
     }
 
     pub fn write_zram(&mut self, addr: u16, value: u8) {
         self.zram[addr as usize] = value;
     }
+
+    // IO: 0xFF70 (CGB only)
+    pub fn read_ram_bank_reg(&self) -> u8 {
+        0xF8 | self.ram_bank
+    }
+
+    pub fn write_ram_bank_reg(&mut self, value: u8) {
+        // Selecting bank 0 maps bank 1
+        self.ram_bank = cmp::max(value & 0x07, 1);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_u8(self.ram_bank);
+        writer.write_bytes(&self.zram);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        try!(reader.read_bytes(&mut self.ram));
+        self.ram_bank = cmp::max(try!(reader.read_u8()) & 0x07, 1);
+        reader.read_bytes(&mut self.zram)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,140 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::io::{self, Write};
+
+// Minimal PNG encoder for 8-bit RGB images. The image data is stored
+// without compression, which keeps this free of external dependencies.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+pub fn write_rgb<W: Write>(out: &mut W, width: usize, height: usize, rgb: &[u8])
+    -> io::Result<()> {
+    assert_eq!(rgb.len(), width * height * 3);
+
+    try!(out.write_all(&SIGNATURE));
+
+    let mut header = Vec::new();
+    push_u32(&mut header, width as u32);
+    push_u32(&mut header, height as u32);
+    // Bit depth 8, color type RGB, default compression, filter and interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    try!(write_chunk(out, b"IHDR", &header));
+
+    // Every scanline starts with its filter type, 0 means none
+    let mut raw = Vec::with_capacity(height * (1 + width * 3));
+    for line in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+    try!(write_chunk(out, b"IDAT", &zlib_stored(&raw)));
+
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    push_u32(&mut chunk, data.len() as u32);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let crc = crc32(&chunk[4..]);
+    push_u32(&mut chunk, crc);
+    out.write_all(&chunk)
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // CMF/FLG: deflate with 32K window, no preset dictionary, lowest level
+    let mut out = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8,
+                                !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+
+    push_u32(&mut out, adler32(data));
+    out
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8,
+                            (value >> 8) as u8, value as u8]);
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::{adler32, crc32, write_chunk, zlib_stored};
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0x00000000);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+    }
+
+    #[test]
+    fn test_chunk() {
+        let mut chunk = Vec::new();
+        write_chunk(&mut chunk, b"IEND", &[]).unwrap();
+        assert_eq!(chunk, [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 0x00000001);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn test_zlib_stored() {
+        let data = vec![0xAB; 0x10000];
+        let out = zlib_stored(&data);
+
+        // A full block and a final one with the remaining byte
+        assert_eq!(&out[..7], &[0x78, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        let second = 7 + 0xFFFF;
+        assert_eq!(&out[second..second + 5], &[0x01, 0x01, 0x00, 0xFE, 0xFF]);
+        assert_eq!(out.len(), 2 + 2 * 5 + data.len() + 4);
+        assert_eq!(&out[out.len() - 4..], &[0x50, 0x37, 0x0A, 0x06]);
+    }
+}
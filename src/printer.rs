@@ -0,0 +1,307 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use png;
+use serial::SerialDevice;
+
+// Game Boy Printer. The Game Boy sends packets of the form
+//   0x88 0x33 command compression len_lo len_hi data[len] sum_lo sum_hi 0x00 0x00
+// and the printer answers the last two bytes with 0x81 and its status.
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_BUSY: u8 = 1 << 1;
+const STATUS_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+const BUFFER_SIZE: usize = 0x2000;
+
+// Time the printer reports being busy after a print command
+const PRINT_CYCLES: u32 = 4194304 / 4 / 2;
+
+const SHADES: [(u8, u8, u8); 4] = [
+    (0xff, 0xff, 0xff),
+    (0xaa, 0xaa, 0xaa),
+    (0x55, 0x55, 0x55),
+    (0x00, 0x00, 0x00),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLo,
+    LengthHi,
+    Data,
+    ChecksumLo,
+    ChecksumHi,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    dir: PathBuf,
+
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+
+    status: u8,
+    busy_cycles: u32,
+
+    // Decompressed tile data received since the last print
+    buffer: Vec<u8>,
+    // RGB rows of the page being printed
+    page: Vec<u8>,
+}
+
+impl Printer {
+    pub fn new(dir: PathBuf) -> Printer {
+        Printer {
+            dir: dir,
+
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+
+            status: 0,
+            busy_cycles: 0,
+
+            buffer: Vec::new(),
+            page: Vec::new(),
+        }
+    }
+
+    fn receive(&mut self, value: u8) -> u8 {
+        use self::PacketState::*;
+
+        let mut reply = 0x00;
+        self.state = match self.state {
+            Magic1 if value == 0x88 => Magic2,
+            Magic1 => Magic1,
+            Magic2 if value == 0x33 => Command,
+            Magic2 => Magic1,
+            Command => {
+                self.command = value;
+                self.checksum = value as u16;
+                Compression
+            },
+            Compression => {
+                self.compressed = value & 0x1 != 0;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                LengthLo
+            },
+            LengthLo => {
+                self.length = value as u16;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                LengthHi
+            },
+            LengthHi => {
+                self.length |= (value as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                self.packet.clear();
+                if self.length == 0 { ChecksumLo } else { Data }
+            },
+            Data => {
+                self.packet.push(value);
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                if self.packet.len() == self.length as usize { ChecksumLo } else { Data }
+            },
+            ChecksumLo => {
+                self.checksum ^= value as u16;
+                ChecksumHi
+            },
+            ChecksumHi => {
+                self.checksum ^= (value as u16) << 8;
+                if self.checksum == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                Alive
+            },
+            Alive => {
+                reply = 0x81;
+                Status
+            },
+            Status => {
+                reply = self.status;
+                Magic1
+            },
+        };
+        reply
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_cycles = 0;
+            },
+            CMD_DATA => {
+                let packet = ::std::mem::replace(&mut self.packet, Vec::new());
+                if self.compressed {
+                    self.decompress(&packet);
+                } else {
+                    self.buffer.extend_from_slice(&packet);
+                }
+                self.buffer.truncate(BUFFER_SIZE);
+
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+                self.packet = packet;
+            },
+            CMD_PRINT if self.packet.len() >= 4 => {
+                let margins = self.packet[1];
+                let palette = self.packet[2];
+                self.print(palette);
+
+                // A margin after the image means the paper gets cut here
+                if margins & 0x0F != 0 {
+                    self.finish_page();
+                }
+
+                self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_FULL)) | STATUS_BUSY;
+                self.busy_cycles = PRINT_CYCLES;
+            },
+            CMD_STATUS => {},
+            _ => {}
+        }
+    }
+
+    // Runs of equal bytes have the high bit set in their control byte and
+    // a length of (control & 0x7F) + 2, literal runs have (control + 1) bytes
+    fn decompress(&mut self, data: &[u8]) {
+        let mut pos = 0;
+        while pos < data.len() {
+            let control = data[pos];
+            pos += 1;
+            if control & 0x80 != 0 {
+                if pos < data.len() {
+                    let len = (control & 0x7F) as usize + 2;
+                    for _ in 0..len {
+                        self.buffer.push(data[pos]);
+                    }
+                    pos += 1;
+                }
+            } else {
+                let end = ::std::cmp::min(pos + control as usize + 1, data.len());
+                self.buffer.extend_from_slice(&data[pos..end]);
+                pos = end;
+            }
+        }
+    }
+
+    // The buffer holds rows of 20 tiles in the usual 2bpp format
+    fn print(&mut self, palette: u8) {
+        // Some games send an empty palette and expect the default one
+        let palette = if palette == 0 { 0xE4 } else { palette };
+
+        let row_size = TILES_PER_ROW * 16;
+        let rows = self.buffer.len() / row_size;
+        for row in 0..rows {
+            for line in 0..8 {
+                for x in 0..WIDTH {
+                    let ofs = row * row_size + (x / 8) * 16 + line * 2;
+                    let shift = 7 - (x % 8);
+                    let lo = (self.buffer[ofs] >> shift) & 0x1;
+                    let hi = (self.buffer[ofs + 1] >> shift) & 0x1;
+                    let color_id = (hi << 1) | lo;
+                    let shade = (palette >> (2 * color_id)) & 0b11;
+                    let (r, g, b) = SHADES[shade as usize];
+                    self.page.extend_from_slice(&[r, g, b]);
+                }
+            }
+        }
+        self.buffer.clear();
+    }
+
+    fn finish_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+
+        let page = ::std::mem::replace(&mut self.page, Vec::new());
+        let height = page.len() / (WIDTH * 3);
+        let path = self.next_page_path();
+
+        let res = File::create(&path).and_then(|file| {
+            png::write_rgb(&mut BufWriter::new(file), WIDTH, height, &page)
+        });
+        match res {
+            Ok(_) => println!("Printed page to '{}'", path.display()),
+            Err(why) => println!("Could not write printed page '{}': {}", path.display(), why)
+        }
+    }
+
+    fn next_page_path(&self) -> PathBuf {
+        let mut index = 1;
+        loop {
+            let path = self.dir.join(format!("print_{:04}.png", index));
+            if !path.exists() {
+                return path;
+            }
+            index += 1;
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, value: u8) -> u8 {
+        self.receive(value)
+    }
+
+    fn update(&mut self, cycles: u8, _: u8, _: bool) -> Option<u8> {
+        if self.busy_cycles > 0 {
+            self.busy_cycles = self.busy_cycles.saturating_sub(cycles as u32);
+            if self.busy_cycles == 0 {
+                self.status &= !STATUS_BUSY;
+            }
+        }
+        // The printer never drives the clock
+        None
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        // Don't lose a page that was printed without a final margin
+        self.finish_page();
+    }
+}
@@ -0,0 +1,224 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use std::collections::VecDeque;
+
+use error::Error;
+use gameboy::Gameboy;
+
+// Upper bound for the memory used by the compressed snapshots
+const MAX_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+
+// Keeps the most recent snapshot in full, and for every older one only the
+// difference to its successor. Consecutive snapshots differ in few bytes, so
+// the XOR of two is mostly zeros and compresses well with run-length encoding.
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    frames: u32,
+
+    current: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    size: usize,
+}
+
+impl Rewind {
+    // Takes a snapshot every `interval` frames and keeps up to `capacity`
+    pub fn new(interval: u32, capacity: usize) -> Rewind {
+        Rewind {
+            interval: if interval == 0 { 1 } else { interval },
+            capacity: capacity,
+            frames: 0,
+
+            current: None,
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    // Called once per emulated frame
+    pub fn record(&mut self, gameboy: &mut Gameboy) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(gameboy.save_state());
+        }
+    }
+
+    fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(current) = self.current.take() {
+            if current.len() == snapshot.len() {
+                let delta = encode_delta(&current, &snapshot);
+                self.size += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.deltas.clear();
+                self.size = 0;
+            }
+        }
+
+        while self.deltas.len() >= self.capacity || self.size > MAX_BUFFER_SIZE {
+            match self.deltas.pop_front() {
+                Some(delta) => self.size -= delta.len(),
+                None => break
+            }
+        }
+        self.current = Some(snapshot);
+    }
+
+    // Restores the most recent snapshot and drops it from the buffer. Once
+    // the oldest snapshot is reached, it stays in place.
+    pub fn step_back(&mut self, gameboy: &mut Gameboy) -> Result<bool, Error> {
+        let snapshot = match self.current.take() {
+            Some(snapshot) => snapshot,
+            None => return Ok(false)
+        };
+        try!(gameboy.load_snapshot(&snapshot));
+        self.frames = 0;
+
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                self.size -= delta.len();
+                let mut previous = snapshot;
+                try!(apply_delta(&delta, &mut previous));
+                self.current = Some(previous);
+            },
+            None => self.current = Some(snapshot)
+        }
+        Ok(true)
+    }
+}
+
+// Delta format: pairs of (number of unchanged bytes, number of changed bytes)
+// as variable-length integers, each followed by the XOR of the changed bytes
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut pos = 0;
+
+    while pos < new.len() {
+        let start = pos;
+        while pos < new.len() && old[pos] == new[pos] {
+            pos += 1;
+        }
+        let unchanged = pos - start;
+
+        let start = pos;
+        while pos < new.len() && old[pos] != new[pos] {
+            pos += 1;
+        }
+
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, pos - start);
+        for i in start..pos {
+            delta.push(old[i] ^ new[i]);
+        }
+    }
+    delta
+}
+
+fn apply_delta(delta: &[u8], data: &mut [u8]) -> Result<(), Error> {
+    let corrupt = || Error::BadState("corrupt rewind snapshot".to_string());
+    let mut ofs = 0;
+    let mut pos = 0;
+
+    while ofs < delta.len() {
+        pos += try!(read_varint(delta, &mut ofs).ok_or_else(&corrupt));
+        let changed = try!(read_varint(delta, &mut ofs).ok_or_else(&corrupt));
+        if pos + changed > data.len() || ofs + changed > delta.len() {
+            return Err(corrupt());
+        }
+        for i in 0..changed {
+            data[pos + i] ^= delta[ofs + i];
+        }
+        pos += changed;
+        ofs += changed;
+    }
+    Ok(())
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &[u8], ofs: &mut usize) -> Option<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match buf.get(*ofs) {
+            Some(&byte) => byte,
+            None => return None
+        };
+        *ofs += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_delta, apply_delta};
+
+    fn roundtrip(old: &[u8], new: &[u8]) {
+        let delta = encode_delta(old, new);
+        let mut data = new.to_vec();
+        apply_delta(&delta, &mut data).unwrap();
+        assert_eq!(&data[..], old);
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        roundtrip(&[], &[]);
+        roundtrip(&[1, 2, 3], &[1, 2, 3]);
+        roundtrip(&[1, 2, 3], &[4, 5, 6]);
+        roundtrip(&[0, 1, 0, 1, 0], &[0, 0, 0, 0, 1]);
+
+        // Runs longer than 127 bytes take more than one varint byte
+        let old: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut new = old.clone();
+        for i in 300..600 {
+            new[i] = !new[i];
+        }
+        new[999] = 0xaa;
+        roundtrip(&old, &new);
+    }
+
+    #[test]
+    fn test_delta_corrupt() {
+        let delta = encode_delta(&[1, 2, 3, 4], &[1, 2, 5, 6]);
+        let mut data = vec![0; 4];
+
+        // Cut off in the middle of the changed bytes
+        assert!(apply_delta(&delta[..delta.len() - 1], &mut data).is_err());
+        // Unfinished varint
+        assert!(apply_delta(&[0x80], &mut data).is_err());
+        // Changes past the end of the data
+        let mut data = vec![0; 2];
+        assert!(apply_delta(&delta, &mut data).is_err());
+    }
+}
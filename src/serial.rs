@@ -0,0 +1,225 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use error::Error;
+use int_controller::{Interrupt, IntController};
+use state::{StateReader, StateWriter};
+
+// With the internal clock, one bit is shifted every 128 cycles (8192 Hz)
+const CYCLES_PER_BIT: u32 = 128;
+
+const TRANSFER_START: u8 = 1 << 7;
+const INTERNAL_CLOCK: u8 = 1 << 0;
+
+// Something plugged into the serial port
+pub trait SerialDevice {
+    // The Game Boy starts a transfer with its internal clock. Returns the
+    // byte the device sends back at the same time.
+    fn transfer(&mut self, value: u8) -> u8;
+
+    // Called on every update. A device that provides the clock itself may
+    // complete a transfer here if the Game Boy waits for one (`ready`),
+    // returning the byte it sent in exchange for `value`.
+    fn update(&mut self, cycles: u8, value: u8, ready: bool) -> Option<u8>;
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+
+    // Byte shifted in during the running transfer
+    incoming: u8,
+    bits: u8,
+    clock: u32,
+
+    device: Option<Box<SerialDevice>>,
+    // Without a device, everything sent ends up here
+    captured: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+
+            incoming: 0xFF,
+            bits: 0,
+            clock: 0,
+
+            device: None,
+            captured: Vec::new(),
+        }
+    }
+
+    pub fn set_device(&mut self, device: Option<Box<SerialDevice>>) {
+        self.device = device;
+    }
+
+    pub fn take_captured(&mut self) -> Vec<u8> {
+        ::std::mem::replace(&mut self.captured, Vec::new())
+    }
+
+    pub fn step(&mut self, cycles: u8, int_controller: &mut IntController) {
+        if self.control & TRANSFER_START == 0 {
+            // Nobody waits for a transfer, but a clocking device may still
+            // expect an answer
+            if let Some(ref mut device) = self.device {
+                device.update(cycles, self.data, false);
+            }
+            return;
+        }
+
+        if self.control & INTERNAL_CLOCK == 0 {
+            let received = match self.device {
+                Some(ref mut device) => device.update(cycles, self.data, true),
+                None => None
+            };
+            if let Some(value) = received {
+                self.data = value;
+                self.finish_transfer(int_controller);
+            }
+            return;
+        }
+
+        if let Some(ref mut device) = self.device {
+            device.update(cycles, self.data, false);
+        }
+
+        self.clock += cycles as u32;
+        while self.clock >= CYCLES_PER_BIT && self.bits < 8 {
+            self.clock -= CYCLES_PER_BIT;
+            let bit = (self.incoming >> (7 - self.bits)) & 0x1;
+            self.data = (self.data << 1) | bit;
+            self.bits += 1;
+        }
+
+        if self.bits == 8 {
+            self.finish_transfer(int_controller);
+        }
+    }
+
+    fn start_transfer(&mut self) {
+        // The exchange with the other side happens up front, the bits are
+        // then shifted in over the duration of the transfer
+        self.incoming = match self.device {
+            Some(ref mut device) => device.transfer(self.data),
+            None => {
+                self.captured.push(self.data);
+                0xFF
+            }
+        };
+        self.bits = 0;
+        self.clock = 0;
+    }
+
+    fn finish_transfer(&mut self, int_controller: &mut IntController) {
+        self.control &= !TRANSFER_START;
+        self.bits = 0;
+        self.clock = 0;
+        int_controller.set_int_pending(Interrupt::SerialTransfer);
+    }
+
+    // IO: 0xFF01
+    pub fn read_data_reg(&self) -> u8 {
+        self.data
+    }
+
+    pub fn write_data_reg(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    // IO: 0xFF02
+    pub fn read_control_reg(&self) -> u8 {
+        self.control | 0x7E
+    }
+
+    pub fn write_control_reg(&mut self, value: u8) {
+        let was_running = self.control & TRANSFER_START != 0;
+        self.control = value & (TRANSFER_START | INTERNAL_CLOCK);
+
+        if !was_running && value & (TRANSFER_START | INTERNAL_CLOCK)
+            == TRANSFER_START | INTERNAL_CLOCK {
+            self.start_transfer();
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+        writer.write_u8(self.incoming);
+        writer.write_u8(self.bits);
+        writer.write_u32(self.clock);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.data = try!(reader.read_u8());
+        self.control = try!(reader.read_u8()) & (TRANSFER_START | INTERNAL_CLOCK);
+        self.incoming = try!(reader.read_u8());
+        self.bits = try!(reader.read_u8()) & 0b111;
+        self.clock = try!(reader.read_u32());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Serial;
+    use int_controller::IntController;
+
+    const SERIAL_PENDING: u8 = 1 << 3;
+
+    #[test]
+    fn test_internal_clock() {
+        let mut serial = Serial::new();
+        let mut int_controller = IntController::new();
+        serial.write_data_reg(0x42);
+        serial.write_control_reg(0x81);
+        assert_eq!(serial.read_control_reg(), 0xFF);
+
+        // 8 bits of 128 cycles each
+        for _ in 0..255 {
+            serial.step(4, &mut int_controller);
+        }
+        assert_eq!(int_controller.read_pending_reg() & SERIAL_PENDING, 0);
+        assert_eq!(serial.read_control_reg() & 0x80, 0x80);
+
+        serial.step(4, &mut int_controller);
+        assert_eq!(int_controller.read_pending_reg() & SERIAL_PENDING, SERIAL_PENDING);
+        assert_eq!(serial.read_control_reg(), 0x7F);
+
+        // Nothing is connected, so the byte is captured and 0xFF shifted in
+        assert_eq!(serial.read_data_reg(), 0xFF);
+        assert_eq!(serial.take_captured(), vec![0x42]);
+        assert!(serial.take_captured().is_empty());
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let mut serial = Serial::new();
+        let mut int_controller = IntController::new();
+        serial.write_data_reg(0x42);
+        serial.write_control_reg(0x80);
+
+        // Without a partner providing the clock, the transfer waits forever
+        for _ in 0..10000 {
+            serial.step(4, &mut int_controller);
+        }
+        assert_eq!(int_controller.read_pending_reg() & SERIAL_PENDING, 0);
+        assert_eq!(serial.read_control_reg(), 0xFE);
+        assert_eq!(serial.read_data_reg(), 0x42);
+        assert!(serial.take_captured().is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+// This file is part of GBEmu.
+// Copyright (C) 2016 Max Seitzer <contact@max-seitzer.de>
+//
+// GBEmu is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// GBEmu is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
+use error::Error;
+
+// Save state layout: magic, format version and the checksums of the ROM the
+// state was made with, followed by the state of every component in turn.
+// All values are stored little-endian.
+const MAGIC: &'static [u8; 8] = b"GBEMUSST";
+pub const VERSION: u16 = 10;
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn write_header(&mut self, header_checksum: u8, global_checksum: u16) {
+        self.write_bytes(MAGIC);
+        self.write_u16(VERSION);
+        self.write_u8(header_checksum);
+        self.write_u16(global_checksum);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_le(value as u64, 2);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_le(value as u64, 4);
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_le(value, 8);
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    fn write_le(&mut self, value: u64, len: usize) {
+        for i in 0..len {
+            self.buf.push((value >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    pub fn read_header(&mut self, header_checksum: u8, global_checksum: u16)
+        -> Result<(), Error> {
+        let mut magic = [0; 8];
+        try!(self.read_bytes(&mut magic));
+        if &magic != MAGIC {
+            return Err(Error::BadState("not a save state".to_string()));
+        }
+
+        let version = try!(self.read_u16());
+        if version != VERSION {
+            return Err(Error::BadState(format!("unsupported version {}", version)));
+        }
+
+        let state_header_checksum = try!(self.read_u8());
+        let state_global_checksum = try!(self.read_u16());
+        if state_header_checksum != header_checksum
+            || state_global_checksum != global_checksum {
+            return Err(Error::StateRomMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        if self.pos >= self.data.len() {
+            return Err(Error::BadState("unexpected end of data".to_string()));
+        }
+        self.pos += 1;
+        Ok(self.data[self.pos - 1])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        Ok(try!(self.read_u8()) != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(try!(self.read_le(2)) as u16)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(try!(self.read_le(4)) as u32)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        self.read_le(8)
+    }
+
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        if self.data.len() - self.pos < out.len() {
+            return Err(Error::BadState("unexpected end of data".to_string()));
+        }
+        out.copy_from_slice(&self.data[self.pos..self.pos + out.len()]);
+        self.pos += out.len();
+        Ok(())
+    }
+
+    fn read_le(&mut self, len: usize) -> Result<u64, Error> {
+        let mut value = 0;
+        for i in 0..len {
+            value |= (try!(self.read_u8()) as u64) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.pos != self.data.len() {
+            return Err(Error::BadState("trailing data".to_string()));
+        }
+        Ok(())
+    }
+}
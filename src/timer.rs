@@ -14,72 +14,101 @@
 // You should have received a copy of the GNU General Public License
 // along with GBEmu.  If not, see <http://www.gnu.org/licenses/>.
 use super::int_controller::{Interrupt, IntController};
+use error::Error;
+use state::{StateReader, StateWriter};
+
+// The timer is driven by a 16-bit counter that increments every T-cycle,
+// DIV being its upper byte. TIMA increments whenever the counter bit
+// selected by TAC, ANDed with the enable bit, goes from 1 to 0.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Reload {
+	None,
+	// TIMA overflowed and reads 0, it is reloaded on the next cycle
+	Pending,
+	// TIMA was just reloaded from TMA
+	Done,
+}
 
 pub struct Timer {
-	divider_reg: u8,
+	counter: u16,
 	counter_reg: u8,
 	modulo_reg: u8,
-
 	control_reg: u8,
-	counter_rate: u8,
-	active: bool,
 
-	threshold: u8,
-	clock: u64,
-
-	divider_threshold: u8,
+	reload: Reload,
 }
 
 impl Timer {
 	pub fn new() -> Timer {
 		Timer {
-			divider_reg: 0,
+			counter: 0,
 			counter_reg: 0,
 			modulo_reg: 0,
 			control_reg: 0,
-			counter_rate: 1,
-			active: false,
-			threshold: 0,
-			clock: 0,
-			divider_threshold: 0
+			reload: Reload::None,
 		}
 	}
 
 	pub fn tick(&mut self, cycles: u8, int_controller: &mut IntController) {
-		self.threshold += cycles;
-
-		if self.threshold >= 4 {
-			// Clock ticks once
-			self.clock += 1;
-			self.threshold -= 4;
-
-			// Divider clock ticks at 1/16th the rate of the main clock
-			self.divider_threshold += 1;
-			if self.divider_threshold == 16 {
-				self.divider_reg = self.divider_reg.wrapping_add(1);
-				self.divider_threshold = 0;
-			}
+		for _ in 0..cycles {
+			self.step(int_controller);
 		}
+	}
 
-		if self.active && self.clock >= self.counter_rate as u64 {
-			self.clock = 0;
-			let (value, overflow) = self.counter_reg.overflowing_add(1);
-
-			if overflow {
+	// One M-cycle
+	fn step(&mut self, int_controller: &mut IntController) {
+		match self.reload {
+			Reload::Pending => {
 				self.counter_reg = self.modulo_reg;
+				self.reload = Reload::Done;
 				int_controller.set_int_pending(Interrupt::Timer);
-			} else {
-				self.counter_reg = value;
-			}
+			},
+			Reload::Done => self.reload = Reload::None,
+			Reload::None => {}
+		}
+
+		let old_bit = self.timer_bit();
+		self.counter = self.counter.wrapping_add(4);
+		if old_bit && !self.timer_bit() {
+			self.increment();
+		}
+	}
+
+	fn timer_bit(&self) -> bool {
+		let bit = match self.control_reg & 0b11 {
+			0b00 => 9,
+			0b01 => 3,
+			0b10 => 5,
+			0b11 => 7,
+			_ => unreachable!()
+		};
+		self.control_reg & 0b100 != 0 && (self.counter >> bit) & 0x1 != 0
+	}
+
+	fn increment(&mut self) {
+		let (value, overflow) = self.counter_reg.overflowing_add(1);
+		self.counter_reg = value;
+		if overflow {
+			self.reload = Reload::Pending;
 		}
 	}
 
 	pub fn read_divider_reg(&self) -> u8 {
-		self.divider_reg
+		(self.counter >> 8) as u8
 	}
 
 	pub fn write_divider_reg(&mut self, _: u8) {
-		self.divider_reg = 0;
+		// Resetting the counter can cause a falling edge
+		let old_bit = self.timer_bit();
+		self.counter = 0;
+		if old_bit {
+			self.increment();
+		}
+	}
+
+	pub fn set_system_counter(&mut self, value: u16) {
+		self.counter = value;
 	}
 
 	pub fn read_counter_reg(&self) -> u8 {
@@ -87,7 +116,16 @@ impl Timer {
 	}
 
 	pub fn write_counter_reg(&mut self, value: u8) {
-		self.counter_reg = value;
+		match self.reload {
+			// Writing during the cycle after an overflow cancels the reload
+			Reload::Pending => {
+				self.reload = Reload::None;
+				self.counter_reg = value;
+			},
+			// Writes in the reload cycle are overwritten by TMA
+			Reload::Done => {},
+			Reload::None => self.counter_reg = value,
+		}
 	}
 
 	pub fn read_modulo_reg(&self) -> u8 {
@@ -96,21 +134,143 @@ impl Timer {
 
 	pub fn write_modulo_reg(&mut self, value: u8) {
 		self.modulo_reg = value;
+		// In the reload cycle, the new value makes it to TIMA as well
+		if self.reload == Reload::Done {
+			self.counter_reg = value;
+		}
 	}
 
 	pub fn read_control_reg(&self) -> u8 {
-		self.control_reg
+		self.control_reg | 0xF8
 	}
 
 	pub fn write_control_reg(&mut self, value: u8) {
-		self.control_reg = value;
-		self.counter_rate = match value & 0b11 {
-			0b00 => 64,
-			0b01 => 1,
-			0b10 => 4,
-			0b11 => 16,
-			_ => panic!("Illegal value selected for timer speed!")
+		// Disabling the timer or switching to another bit can cause a falling edge
+		let old_bit = self.timer_bit();
+		self.control_reg = value & 0b111;
+		if old_bit && !self.timer_bit() {
+			self.increment();
+		}
+	}
+
+	pub fn save_state(&self, writer: &mut StateWriter) {
+		writer.write_u16(self.counter);
+		writer.write_u8(self.counter_reg);
+		writer.write_u8(self.modulo_reg);
+		writer.write_u8(self.control_reg);
+		writer.write_u8(self.reload as u8);
+	}
+
+	pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+		self.counter = try!(reader.read_u16());
+		self.counter_reg = try!(reader.read_u8());
+		self.modulo_reg = try!(reader.read_u8());
+		self.control_reg = try!(reader.read_u8()) & 0b111;
+		self.reload = match try!(reader.read_u8()) {
+			0 => Reload::None,
+			1 => Reload::Pending,
+			2 => Reload::Done,
+			_ => return Err(Error::BadState("invalid timer reload state".to_string()))
 		};
-		self.active = value & 0b100 != 0;
+		Ok(())
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+	use super::Timer;
+	use int_controller::IntController;
+
+	const TIMER_PENDING: u8 = 1 << 2;
+
+	// TIMA about to overflow on the next step, which counts bit 3 down
+	fn overflowing_timer() -> (Timer, IntController) {
+		let mut timer = Timer::new();
+		timer.write_control_reg(0b101);
+		timer.write_counter_reg(0xff);
+		timer.write_modulo_reg(0x42);
+		timer.set_system_counter(0x000c);
+		(timer, IntController::new())
+	}
+
+	#[test]
+	fn test_div_write_falling_edge() {
+		let mut timer = Timer::new();
+		timer.write_control_reg(0b101);
+
+		timer.set_system_counter(0x0008);
+		timer.write_divider_reg(0x12);
+		assert_eq!(timer.read_counter_reg(), 1);
+		assert_eq!(timer.read_divider_reg(), 0);
+
+		// Without the selected bit set, there is no edge
+		timer.set_system_counter(0x0100);
+		timer.write_divider_reg(0x12);
+		assert_eq!(timer.read_counter_reg(), 1);
+	}
+
+	#[test]
+	fn test_tac_falling_edge() {
+		let mut timer = Timer::new();
+		timer.write_control_reg(0b101);
+		timer.set_system_counter(0x0008);
+
+		// Disabling the timer
+		timer.write_control_reg(0b001);
+		assert_eq!(timer.read_counter_reg(), 1);
+
+		// Switching from bit 3 to the cleared bit 5
+		timer.write_control_reg(0b101);
+		timer.write_control_reg(0b110);
+		assert_eq!(timer.read_counter_reg(), 2);
+
+		// Switching to another set bit
+		timer.set_system_counter(0x0028);
+		timer.write_control_reg(0b101);
+		assert_eq!(timer.read_counter_reg(), 2);
+	}
+
+	#[test]
+	fn test_reload_delay() {
+		let (mut timer, mut int_controller) = overflowing_timer();
+
+		// TIMA reads 0 for one cycle before TMA is loaded
+		timer.tick(1, &mut int_controller);
+		assert_eq!(timer.read_counter_reg(), 0x00);
+		assert_eq!(int_controller.read_pending_reg() & TIMER_PENDING, 0);
+
+		timer.tick(1, &mut int_controller);
+		assert_eq!(timer.read_counter_reg(), 0x42);
+		assert_eq!(int_controller.read_pending_reg() & TIMER_PENDING, TIMER_PENDING);
+	}
+
+	#[test]
+	fn test_reload_cancel() {
+		let (mut timer, mut int_controller) = overflowing_timer();
+
+		timer.tick(1, &mut int_controller);
+		timer.write_counter_reg(0x10);
+		timer.tick(1, &mut int_controller);
+
+		assert_eq!(timer.read_counter_reg(), 0x10);
+		assert_eq!(int_controller.read_pending_reg() & TIMER_PENDING, 0);
+	}
+
+	#[test]
+	fn test_reload_tma_write() {
+		let (mut timer, mut int_controller) = overflowing_timer();
+
+		timer.tick(2, &mut int_controller);
+
+		// TIMA writes in the reload cycle are lost, TMA writes go through
+		timer.write_counter_reg(0x10);
+		assert_eq!(timer.read_counter_reg(), 0x42);
+		timer.write_modulo_reg(0x99);
+		assert_eq!(timer.read_counter_reg(), 0x99);
+
+		// Afterwards, TMA is only used on the next overflow
+		timer.tick(1, &mut int_controller);
+		timer.write_modulo_reg(0x55);
+		assert_eq!(timer.read_counter_reg(), 0x99);
+	}
+}